@@ -1,31 +1,31 @@
-pub static BIGREQ: &'static [u8] = include_bytes!("../xmlxcb/src/bigreq.xml");
-pub static GLX: &'static [u8] = include_bytes!("../xmlxcb/src/glx.xml");
-pub static SCREENSAVER: &'static [u8] = include_bytes!("../xmlxcb/src/screensaver.xml");
-pub static XF86DRI: &'static [u8] = include_bytes!("../xmlxcb/src/xf86dri.xml");
-pub static XPROTO: &'static [u8] = include_bytes!("../xmlxcb/src/xproto.xml");
-pub static COMPOSITE: &'static [u8] = include_bytes!("../xmlxcb/src/composite.xml");
-pub static SHAPE: &'static [u8] = include_bytes!("../xmlxcb/src/shape.xml");
-pub static XF86VIDMODE: &'static [u8] = include_bytes!("../xmlxcb/src/xf86vidmode.xml");
-pub static XSELINUX: &'static [u8] = include_bytes!("../xmlxcb/src/xselinux.xml");
-pub static DAMAGE: &'static [u8] = include_bytes!("../xmlxcb/src/damage.xml");
-pub static PRESENT: &'static [u8] = include_bytes!("../xmlxcb/src/present.xml");
-pub static SHM: &'static [u8] = include_bytes!("../xmlxcb/src/shm.xml");
-pub static XFIXES: &'static [u8] = include_bytes!("../xmlxcb/src/xfixes.xml");
-pub static XTEST: &'static [u8] = include_bytes!("../xmlxcb/src/xtest.xml");
-pub static DPMS: &'static [u8] = include_bytes!("../xmlxcb/src/dpms.xml");
-pub static RANDR: &'static [u8] = include_bytes!("../xmlxcb/src/randr.xml");
-pub static SYNC: &'static [u8] = include_bytes!("../xmlxcb/src/sync.xml");
-pub static XINERAMA: &'static [u8] = include_bytes!("../xmlxcb/src/xinerama.xml");
-pub static XVMC: &'static [u8] = include_bytes!("../xmlxcb/src/xvmc.xml");
-pub static DRI2: &'static [u8] = include_bytes!("../xmlxcb/src/dri2.xml");
-pub static RECORD: &'static [u8] = include_bytes!("../xmlxcb/src/record.xml");
-pub static XINPUT: &'static [u8] = include_bytes!("../xmlxcb/src/xinput.xml");
-pub static XV: &'static [u8] = include_bytes!("../xmlxcb/src/xv.xml");
-pub static DRI3: &'static [u8] = include_bytes!("../xmlxcb/src/dri3.xml");
-pub static RENDER: &'static [u8] = include_bytes!("../xmlxcb/src/render.xml");
-pub static XC_MISC: &'static [u8] = include_bytes!("../xmlxcb/src/xc_misc.xml");
-pub static XKB: &'static [u8] = include_bytes!("../xmlxcb/src/xkb.xml");
-pub static GE: &'static [u8] = include_bytes!("../xmlxcb/src/ge.xml");
-pub static RES: &'static [u8] = include_bytes!("../xmlxcb/src/res.xml");
-pub static XEVIE: &'static [u8] = include_bytes!("../xmlxcb/src/xevie.xml");
-pub static XPRINT: &'static [u8] = include_bytes!("../xmlxcb/src/xprint.xml");
+pub static BIGREQ: &[u8] = include_bytes!("../xmlxcb/src/bigreq.xml");
+pub static GLX: &[u8] = include_bytes!("../xmlxcb/src/glx.xml");
+pub static SCREENSAVER: &[u8] = include_bytes!("../xmlxcb/src/screensaver.xml");
+pub static XF86DRI: &[u8] = include_bytes!("../xmlxcb/src/xf86dri.xml");
+pub static XPROTO: &[u8] = include_bytes!("../xmlxcb/src/xproto.xml");
+pub static COMPOSITE: &[u8] = include_bytes!("../xmlxcb/src/composite.xml");
+pub static SHAPE: &[u8] = include_bytes!("../xmlxcb/src/shape.xml");
+pub static XF86VIDMODE: &[u8] = include_bytes!("../xmlxcb/src/xf86vidmode.xml");
+pub static XSELINUX: &[u8] = include_bytes!("../xmlxcb/src/xselinux.xml");
+pub static DAMAGE: &[u8] = include_bytes!("../xmlxcb/src/damage.xml");
+pub static PRESENT: &[u8] = include_bytes!("../xmlxcb/src/present.xml");
+pub static SHM: &[u8] = include_bytes!("../xmlxcb/src/shm.xml");
+pub static XFIXES: &[u8] = include_bytes!("../xmlxcb/src/xfixes.xml");
+pub static XTEST: &[u8] = include_bytes!("../xmlxcb/src/xtest.xml");
+pub static DPMS: &[u8] = include_bytes!("../xmlxcb/src/dpms.xml");
+pub static RANDR: &[u8] = include_bytes!("../xmlxcb/src/randr.xml");
+pub static SYNC: &[u8] = include_bytes!("../xmlxcb/src/sync.xml");
+pub static XINERAMA: &[u8] = include_bytes!("../xmlxcb/src/xinerama.xml");
+pub static XVMC: &[u8] = include_bytes!("../xmlxcb/src/xvmc.xml");
+pub static DRI2: &[u8] = include_bytes!("../xmlxcb/src/dri2.xml");
+pub static RECORD: &[u8] = include_bytes!("../xmlxcb/src/record.xml");
+pub static XINPUT: &[u8] = include_bytes!("../xmlxcb/src/xinput.xml");
+pub static XV: &[u8] = include_bytes!("../xmlxcb/src/xv.xml");
+pub static DRI3: &[u8] = include_bytes!("../xmlxcb/src/dri3.xml");
+pub static RENDER: &[u8] = include_bytes!("../xmlxcb/src/render.xml");
+pub static XC_MISC: &[u8] = include_bytes!("../xmlxcb/src/xc_misc.xml");
+pub static XKB: &[u8] = include_bytes!("../xmlxcb/src/xkb.xml");
+pub static GE: &[u8] = include_bytes!("../xmlxcb/src/ge.xml");
+pub static RES: &[u8] = include_bytes!("../xmlxcb/src/res.xml");
+pub static XEVIE: &[u8] = include_bytes!("../xmlxcb/src/xevie.xml");
+pub static XPRINT: &[u8] = include_bytes!("../xmlxcb/src/xprint.xml");
@@ -0,0 +1,283 @@
+use xml::attribute::OwnedAttribute;
+use xml::reader::EventReader;
+use xml::reader::XmlEvent;
+
+use std::io::Read;
+
+use {get_attribute, recv, rustyfi_name, TypesInfo};
+
+/// An expression of the XCB language, used for example to compute the length of lists.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expression {
+    /// `<value>N</value>`
+    Value(u32),
+    /// `<bit>N</bit>`
+    Bit(u32),
+    /// `<fieldref>name</fieldref>`
+    FieldRef(String),
+    /// `<paramref type="...">name</paramref>`
+    ParamRef(String),
+    /// `<enumref ref="Enum">Item</enumref>`
+    EnumRef(String, String),
+    /// `<op op="...">expression expression</op>`
+    Op(String, Box<Expression>, Box<Expression>),
+    /// `<unop op="...">expression</unop>`
+    Unop(String, Box<Expression>),
+    /// `<sumof ref="list">[expression]</sumof>`
+    SumOf(String, Option<Box<Expression>>),
+    /// `<popcount>expression</popcount>`
+    PopCount(Box<Expression>),
+    /// `<listelement-ref />`, the current element of the list in a `<sumof>`
+    ListElementRef,
+}
+
+/// Gives access to the values that an expression can refer to.
+pub trait ExpressionContext {
+    /// Returns the code that gives the value of a field as a `usize`.
+    fn field(&self, name: &str) -> String;
+
+    /// Returns the code that gives the value of a parameter as a `usize`.
+    fn param(&self, name: &str) -> String;
+
+    /// Returns the code that gives access to a list, on which `.iter()` can be called.
+    fn list(&self, name: &str) -> String;
+
+    /// Returns the value of an item of an enum.
+    fn enum_item(&self, enum_name: &str, item: &str) -> u32;
+}
+
+/// Context used for the expression inside a `<sumof>`, where fields refer to the current element.
+struct ElementContext<'a> {
+    parent: &'a dyn ExpressionContext,
+}
+
+impl<'a> ExpressionContext for ElementContext<'a> {
+    fn field(&self, name: &str) -> String {
+        format!("(elem.{} as usize)", name)
+    }
+
+    fn param(&self, name: &str) -> String {
+        self.parent.param(name)
+    }
+
+    fn list(&self, name: &str) -> String {
+        format!("elem.{}", name)
+    }
+
+    fn enum_item(&self, enum_name: &str, item: &str) -> u32 {
+        self.parent.enum_item(enum_name, item)
+    }
+}
+
+impl Expression {
+    /// Returns true if `name` is the name of an element that starts an expression.
+    pub fn is_expression(name: &str) -> bool {
+        matches!(name, "value" | "bit" | "fieldref" | "paramref" | "enumref" | "op" | "unop" |
+                       "sumof" | "popcount" | "listelement-ref")
+    }
+
+    /// Parses an expression whose start element has just been read.
+    pub fn parse<R>(tag: &str, attributes: &[OwnedAttribute], events: &mut EventReader<R>)
+                    -> Expression where R: Read
+    {
+        let expression = match tag {
+            "value" => Expression::Value(parse_expression_number(&read_text(events))),
+            "bit" => Expression::Bit(parse_expression_number(&read_text(events))),
+            "fieldref" => Expression::FieldRef(rustyfi_name(read_text(events))),
+            "paramref" => Expression::ParamRef(rustyfi_name(read_text(events))),
+            "enumref" => {
+                let enum_name = get_attribute(attributes, "ref").unwrap();
+                Expression::EnumRef(enum_name, read_text(events))
+            },
+            "op" => {
+                let op = get_attribute(attributes, "op").unwrap();
+                let left = parse_child(events).unwrap();
+                let right = parse_child(events).unwrap();
+                Expression::Op(op, Box::new(left), Box::new(right))
+            },
+            "unop" => {
+                let op = get_attribute(attributes, "op").unwrap();
+                Expression::Unop(op, Box::new(parse_child(events).unwrap()))
+            },
+            "sumof" => {
+                let list = rustyfi_name(get_attribute(attributes, "ref").unwrap());
+                match parse_child(events) {
+                    Some(expr) => Expression::SumOf(list, Some(Box::new(expr))),
+                    None => return Expression::SumOf(list, None),
+                }
+            },
+            "popcount" => Expression::PopCount(Box::new(parse_child(events).unwrap())),
+            "listelement-ref" => return match recv(events) {
+                XmlEvent::EndElement{..} => Expression::ListElementRef,
+                msg => panic!("Unexpected {:?} in `<listelement-ref>`", msg),
+            },
+            _ => panic!("Unknown expression `<{}>`", tag)
+        };
+
+        // reading the end of the element
+        match recv(events) {
+            XmlEvent::EndElement{ref name} if name.local_name == tag => (),
+            msg => panic!("Unexpected {:?} in `<{}>`", msg, tag),
+        }
+
+        expression
+    }
+
+    /// Builds the Rust code that computes the expression as a `usize`.
+    pub fn to_code(&self, context: &dyn ExpressionContext) -> String {
+        match *self {
+            Expression::Value(value) => format!("{}usize", value),
+            Expression::Bit(bit) => format!("(1usize << {})", bit),
+            Expression::FieldRef(ref name) => context.field(name),
+            Expression::ParamRef(ref name) => context.param(name),
+            Expression::EnumRef(ref enum_name, ref item) => {
+                format!("{}usize", context.enum_item(enum_name, item))
+            },
+            Expression::Op(ref op, ref left, ref right) => {
+                let left = left.to_code(context);
+                let right = right.to_code(context);
+
+                match &op[..] {
+                    "+" => format!("{}.wrapping_add({})", left, without_parens(&right)),
+                    "-" => format!("{}.wrapping_sub({})", left, without_parens(&right)),
+                    "*" => format!("{}.wrapping_mul({})", left, without_parens(&right)),
+                    "/" | "&" | "|" | "<<" | ">>" => format!("({} {} {})", left, op, right),
+                    _ => panic!("Unknown operator `{}`", op)
+                }
+            },
+            Expression::Unop(ref op, ref expr) => {
+                match &op[..] {
+                    "~" => format!("(!{})", expr.to_code(context)),
+                    _ => panic!("Unknown unary operator `{}`", op)
+                }
+            },
+            Expression::SumOf(ref list, None) => {
+                format!("{}.iter().fold(0usize, |sum, elem| sum + (*elem as usize))",
+                        context.list(list))
+            },
+            Expression::SumOf(ref list, Some(ref expr)) => {
+                let elem_context = ElementContext { parent: context };
+                format!("{}.iter().fold(0usize, |sum, elem| sum + {})", context.list(list),
+                        expr.to_code(&elem_context))
+            },
+            Expression::PopCount(ref expr) => {
+                format!("({}.count_ones() as usize)", expr.to_code(context))
+            },
+            Expression::ListElementRef => "(*elem as usize)".to_string(),
+        }
+    }
+
+    /// Returns the value of the expression if it doesn't depend on any field, parameter or list.
+    pub fn evaluate(&self, types: &TypesInfo) -> Option<u32> {
+        match *self {
+            Expression::Value(value) => Some(value),
+            Expression::Bit(bit) => Some(1 << bit),
+            Expression::EnumRef(ref enum_name, ref item) => Some(types.enum_item(enum_name, item)),
+            Expression::Op(ref op, ref left, ref right) => {
+                let left = left.evaluate(types)?;
+                let right = right.evaluate(types)?;
+
+                match &op[..] {
+                    "+" => Some(left.wrapping_add(right)),
+                    "-" => Some(left.wrapping_sub(right)),
+                    "*" => Some(left.wrapping_mul(right)),
+                    "/" => Some(left / right),
+                    "&" => Some(left & right),
+                    "|" => Some(left | right),
+                    "<<" => Some(left << right),
+                    ">>" => Some(left >> right),
+                    _ => panic!("Unknown operator `{}`", op)
+                }
+            },
+            Expression::Unop(ref op, ref expr) if op == "~" => expr.evaluate(types).map(|v| !v),
+            Expression::PopCount(ref expr) => expr.evaluate(types).map(|v| v.count_ones()),
+            _ => None
+        }
+    }
+
+    /// Adds to `output` the names of all the fields that the expression refers to.
+    pub fn field_refs(&self, output: &mut Vec<String>) {
+        match *self {
+            Expression::FieldRef(ref name) => output.push(name.clone()),
+            Expression::Op(_, ref left, ref right) => {
+                left.field_refs(output);
+                right.field_refs(output);
+            },
+            Expression::Unop(_, ref expr) | Expression::PopCount(ref expr) => {
+                expr.field_refs(output)
+            },
+            _ => ()
+        }
+    }
+
+    /// Adds to `output` the names of all the parameters that the expression refers to.
+    pub fn param_refs(&self, output: &mut Vec<String>) {
+        match *self {
+            Expression::ParamRef(ref name) => output.push(name.clone()),
+            Expression::Op(_, ref left, ref right) => {
+                left.param_refs(output);
+                right.param_refs(output);
+            },
+            Expression::Unop(_, ref expr) | Expression::PopCount(ref expr) => {
+                expr.param_refs(output)
+            },
+            Expression::SumOf(_, Some(ref expr)) => expr.param_refs(output),
+            _ => ()
+        }
+    }
+}
+
+/// Parses the next child expression, or returns `None` if the end of the parent was reached.
+fn parse_child<R>(events: &mut EventReader<R>) -> Option<Expression> where R: Read {
+    match recv(events) {
+        XmlEvent::StartElement{ref name, ref attributes, ..} => {
+            Some(Expression::parse(&name.local_name, attributes, events))
+        },
+        XmlEvent::EndElement{..} => None,
+        msg => panic!("Unexpected {:?} in expression", msg),
+    }
+}
+
+/// Removes the parentheses around the code of an expression, for the places where it isn't
+/// part of a larger expression.
+pub fn without_parens(code: &str) -> &str {
+    if !code.starts_with('(') || !code.ends_with(')') {
+        return code;
+    }
+
+    // the first parenthesis must be closed by the last one, unlike in `(a as usize) + (b)`
+    let mut depth = 0;
+    for (index, c) in code.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            _ => ()
+        }
+
+        if depth == 0 {
+            return if index == code.len() - 1 { &code[1 .. index] } else { code };
+        }
+    }
+
+    code
+}
+
+/// Reads the text content of an element. The end of the element is not consumed.
+fn read_text<R>(events: &mut EventReader<R>) -> String where R: Read {
+    match recv(events) {
+        XmlEvent::Characters(text) => text.trim().to_string(),
+        msg => panic!("Expected some text, found: {:?}", msg),
+    }
+}
+
+/// Parses a decimal number, or a hexadecimal one if it starts with `0x`.
+pub fn parse_number(text: &str) -> Option<u32> {
+    match text.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+fn parse_expression_number(text: &str) -> u32 {
+    parse_number(text).unwrap_or_else(|| panic!("Invalid number `{}` in expression", text))
+}
@@ -1,17 +1,35 @@
 extern crate xmlxcb;
 extern crate xml;
 
+use std::collections::{HashMap, HashSet};
 use std::env;
 use std::fs::File;
 use std::io::{Cursor, Read, Write};
 use std::path::Path;
 
 use xml::reader::EventReader;
-use xml::reader::events::XmlEvent;
+use xml::reader::XmlEvent;
 
+use expression::parse_number;
 use struct_parser::{StructContentParser, StructType};
 
+mod expression;
 mod struct_parser;
+mod value_lists;
+
+/// Types defined by the template or by the prelude, whose names can't be used by the XML
+/// definitions.
+const RESERVED_TYPES: &[&str] = &["XConnection", "ConnectError", "ByteOrder",
+                                                   "Reply", "ReplyType", "ReplyError", "Event",
+                                                   "XError", "UnknownError", "Option", "Result"];
+
+/// Requests that get a series of replies, with an expression that is true for the last reply
+/// of the series and whether this last reply only marks the end of the series.
+const MULTI_REPLY_REQUESTS: &[(&str, &str, bool)] = &[
+    ("ListFontsWithInfo", "reply.name.is_empty()", true),
+    // the last reply has the `EndOfData` category
+    ("RecordEnableContext", "reply.category == 5", false),
+];
 
 fn main() {
     let dest = env::var("OUT_DIR").unwrap();
@@ -21,22 +39,61 @@ fn main() {
         typedefs: Vec::new(),
         replies_list: Vec::new(),
         replies_types: Vec::new(),
+        replies_decoders: Vec::new(),
+        replies_last: Vec::new(),
+        replies_end_markers: Vec::new(),
         events_list: Vec::new(),
+        events_decoders: Vec::new(),
+        generic_events_decoders: Vec::new(),
+        generic_events: Vec::new(),
         errors_list: Vec::new(),
+        errors_decoders: Vec::new(),
+        errors_headers: Vec::new(),
+        errors_requests: Vec::new(),
+        requests_names: Vec::new(),
         requests_list: Vec::new(),
+        altenums: Vec::new(),
+        extension: None,
+        types: TypesInfo {
+            xidtypes: Vec::new(),
+            enums: HashMap::new(),
+            struct_params: HashMap::new(),
+            sizes: primitive_sizes(),
+            errors_with_bad_value: Vec::new(),
+            names: RESERVED_TYPES.iter().map(|ty| ty.to_string()).collect(),
+            renames: HashMap::new(),
+        },
     };
 
     parse(&mut parse_result, Cursor::new(xmlxcb::XPROTO));
-
-    let mut file = File::create(&dest.join("output.rs")).unwrap();
+    parse(&mut parse_result, Cursor::new(xmlxcb::XC_MISC));
+    parse(&mut parse_result, Cursor::new(xmlxcb::BIGREQ));
+    parse(&mut parse_result, Cursor::new(xmlxcb::RECORD));
+    parse(&mut parse_result, Cursor::new(xmlxcb::GE));
+    // the types used by Present and XInput, which deliver most of their events as generic events
+    parse(&mut parse_result, Cursor::new(xmlxcb::RENDER));
+    parse(&mut parse_result, Cursor::new(xmlxcb::SHAPE));
+    parse(&mut parse_result, Cursor::new(xmlxcb::XFIXES));
+    parse(&mut parse_result, Cursor::new(xmlxcb::RANDR));
+    parse(&mut parse_result, Cursor::new(xmlxcb::SYNC));
+    parse(&mut parse_result, Cursor::new(xmlxcb::PRESENT));
+    parse(&mut parse_result, Cursor::new(xmlxcb::XINPUT));
+    write_altenum_conversions(&mut parse_result);
+
+    let mut file = File::create(dest.join("output.rs")).unwrap();
     writeln!(&mut file, r#"
 extern crate byteorder;
 
 use byteorder::{{ReadBytesExt, WriteBytesExt, BigEndian, LittleEndian}};
+use std::convert::TryFrom;
+use std::collections::{{HashMap, VecDeque}};
 use std::net::{{ToSocketAddrs, TcpStream}};
-use std::sync::Mutex;
-use std::sync::atomic::{{AtomicUsize, Ordering}};
+use std::sync::{{Condvar, Mutex}};
+use std::io::{{Read, Write}};
+use std::io::Cursor as IoCursor;
+use std::io::{{Error as IoError, ErrorKind}};
 use std::io::Result as IoResult;
+use std::ops::{{BitAnd, BitOr, BitXor, Not}};
 
 pub type BYTE = u8;
 pub type INT8 = i8;
@@ -45,71 +102,353 @@ pub type INT32 = i32;
 pub type CARD8 = u8;
 pub type CARD16 = u16;
 pub type CARD32 = u32;
+pub type CARD64 = u64;
 pub type BOOL = bool;
 
-/// Represents a connection to an X server.
-pub struct XConnection {{
-    socket: Mutex<TcpStream>,
-    
-    // sequence number attributed to the next request
-    sequence: AtomicUsize,
+/// Represents a connection to an X server, over a stream of type `S`.
+///
+/// The methods are implemented for `XConnection<dyn Stream>`, to which every connection
+/// dereferences, so that the type of the stream can be left out.
+pub struct XConnection<S: ?Sized + Stream = dyn Stream> {{
+    // sequence numbers of the requests sent and of the packets received
+    sequence: Mutex<SequenceTracker>,
+
+    // bytes received from the server that don't form a whole packet yet
+    read_buffer: Mutex<Vec<u8>>,
+
+    // whether a thread is reading from the socket, and the number of packets dispatched
+    reader: Mutex<ReaderState>,
+
+    // notified when the thread that reads from the socket is done
+    reader_done: Condvar,
 
     // list of received events that have to be retreived by the user
-    pending_events: Mutex<Vec<Event>>,
+    pending_events: Mutex<VecDeque<Event>>,
 
     // list of answers that have to be retreived by the user
-    pending_answers: Mutex<Vec<(u16, Reply)>>,
+    pending_answers: Mutex<Vec<(u64, Reply)>>,
+
+    // sequence numbers of requests waiting for an answer, and the type of their reply if
+    // they have one
+    waiting_for_answer: Mutex<Vec<(u64, Option<ReplyType>)>>,
+
+    // information sent by the server during the handshake
+    setup: Setup,
+
+    // index of the screen to use by default, as given by the display name
+    default_screen: usize,
+
+    // byte order of the numbers that we send and receive
+    byte_order: ByteOrder,
+
+    // extensions that have been queried, by name
+    extensions: Mutex<HashMap<&'static str, Option<QueryExtensionReply>>>,
+
+    // gives out the IDs of the resources that we create
+    xid: Mutex<XidAllocator>,
+
+    // maximum length of a request in 4-byte units, once BIG-REQUESTS has been queried
+    maximum_request_length: Mutex<Option<u32>>,
+
+    // asynchronous tasks to wake up when packets are received
+    tasks: Mutex<WaitingTasks>,
+
+    // file descriptor of the socket, if the stream has one
+    #[cfg(unix)]
+    raw_fd: Option<::std::os::unix::io::RawFd>,
+
+    // the requests are gathered in the buffer of the stream until they are flushed; the stream
+    // comes last, so that `XConnection<S>` can be turned into `XConnection<dyn Stream>`
+    socket: Mutex<BufferedStream<S>>,
+}}
+
+/// Error that can happen while connecting to an X server.
+#[derive(Debug)]
+pub enum ConnectError {{
+    /// Error while communicating with the server.
+    IoError(IoError),
+
+    /// The server refused the connection.
+    SetupFailed(SetupFailed),
+
+    /// The server requires a further authentication.
+    SetupAuthenticate(SetupAuthenticate),
+
+    /// The `DISPLAY` environment variable is not set.
+    DisplayNotSet,
+
+    /// The display name couldn't be parsed.
+    InvalidDisplay(String),
+}}
+
+impl From<IoError> for ConnectError {{
+    fn from(err: IoError) -> ConnectError {{
+        ConnectError::IoError(err)
+    }}
+}}
+
+impl ::std::fmt::Display for ConnectError {{
+    fn fmt(&self, fmt: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {{
+        match *self {{
+            ConnectError::IoError(ref err) => write!(fmt, "{{}}", err),
+            ConnectError::SetupFailed(ref failed) => {{
+                write!(fmt, "The X server refused the connection (protocol {{}}.{{}}): {{}}",
+                       failed.protocol_major_version, failed.protocol_minor_version,
+                       String::from_utf8_lossy(&failed.reason))
+            }},
+            ConnectError::SetupAuthenticate(ref auth) => {{
+                write!(fmt, "The X server requires authentication: {{}}",
+                       String::from_utf8_lossy(&auth.reason).trim_end_matches('\0'))
+            }},
+            ConnectError::DisplayNotSet => write!(fmt, "The DISPLAY environment variable is not set"),
+            ConnectError::InvalidDisplay(ref name) => write!(fmt, "Invalid display name: `{{}}`", name),
+        }}
+    }}
+}}
+
+impl ::std::error::Error for ConnectError {{
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {{
+        match *self {{
+            ConnectError::IoError(ref err) => Some(err),
+            _ => None
+        }}
+    }}
+}}
+
+/// Error that can happen while waiting for a reply.
+#[derive(Debug)]
+pub enum ReplyError {{
+    /// Error while communicating with the server, or the reply couldn't be decoded.
+    IoError(IoError),
+
+    /// The server returned an error instead of the reply.
+    XError(XError),
+}}
+
+impl From<IoError> for ReplyError {{
+    fn from(err: IoError) -> ReplyError {{
+        ReplyError::IoError(err)
+    }}
+}}
+
+impl From<XError> for ReplyError {{
+    fn from(err: XError) -> ReplyError {{
+        ReplyError::XError(err)
+    }}
+}}
+
+impl ::std::fmt::Display for ReplyError {{
+    fn fmt(&self, fmt: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {{
+        match *self {{
+            ReplyError::IoError(ref err) => write!(fmt, "{{}}", err),
+            ReplyError::XError(ref err) => write!(fmt, "{{}}", err),
+        }}
+    }}
+}}
 
-    // sequence numbers of requests waiting for an answer
-    waiting_for_answer: Mutex<Vec<(u16, ReplyType)>>,
+impl ::std::error::Error for ReplyError {{
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {{
+        match *self {{
+            ReplyError::IoError(ref err) => Some(err),
+            ReplyError::XError(ref err) => Some(err),
+        }}
+    }}
 }}
 
         "#).unwrap();
     file.write_all(&parse_result.typedefs).unwrap();
     writeln!(&mut file, r#"
 
-/// Iterator for the events received by the server.
-pub struct Events<'a> {{
-    connection: &'a mut XConnection,
+trait SocketSend {{
+    fn socket_send<W: Write + ?Sized>(&self, socket: &mut W, order: ByteOrder) -> IoResult<()>;
+
+    /// Returns the number of bytes that `socket_send` writes.
+    fn wire_size(&self) -> usize;
 }}
 
-trait SocketSend {{
-    fn socket_send(&self, socket: &mut TcpStream) -> IoResult<()>;
+trait SocketRecv: Sized {{
+    fn socket_recv(cursor: &mut IoCursor<&[u8]>, order: ByteOrder) -> IoResult<Self>;
+}}
+
+fn invalid_value(what: &str) -> IoError {{
+    IoError::new(ErrorKind::InvalidData, format!("Invalid value for `{{}}`", what))
+}}
+
+fn list_too_long(what: &str) -> IoError {{
+    IoError::new(ErrorKind::InvalidInput, format!("The list `{{}}` is too long", what))
+}}
+
+/// The byte order of the numbers sent and received, chosen by the client in the setup request.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ByteOrder {{
+    BigEndian,
+    LittleEndian,
+}}
+
+impl ByteOrder {{
+    /// Returns the byte order of the machine, which avoids any conversion on our side.
+    pub fn native() -> ByteOrder {{
+        if cfg!(target_endian = "big") {{
+            ByteOrder::BigEndian
+        }} else {{
+            ByteOrder::LittleEndian
+        }}
+    }}
+
+    /// Returns the byte that identifies the byte order in the setup request.
+    fn setup_byte(&self) -> u8 {{
+        match *self {{
+            ByteOrder::BigEndian => b'B',
+            ByteOrder::LittleEndian => b'l',
+        }}
+    }}
+}}
+
+impl Default for ByteOrder {{
+    fn default() -> ByteOrder {{
+        ByteOrder::native()
+    }}
+}}
+
+impl SocketSend for bool {{
+    fn socket_send<W: Write + ?Sized>(&self, socket: &mut W, _: ByteOrder) -> IoResult<()> {{
+        socket.write_u8(if *self {{ 1 }} else {{ 0 }})
+    }}
+
+    fn wire_size(&self) -> usize {{ 1 }}
 }}
 
 impl SocketSend for i8 {{
-    fn socket_send(&self, socket: &mut TcpStream) -> IoResult<()> {{
+    fn socket_send<W: Write + ?Sized>(&self, socket: &mut W, _: ByteOrder) -> IoResult<()> {{
         socket.write_i8(*self)
     }}
+
+    fn wire_size(&self) -> usize {{ 1 }}
 }}
 
 impl SocketSend for u8 {{
-    fn socket_send(&self, socket: &mut TcpStream) -> IoResult<()> {{
+    fn socket_send<W: Write + ?Sized>(&self, socket: &mut W, _: ByteOrder) -> IoResult<()> {{
         socket.write_u8(*self)
     }}
+
+    fn wire_size(&self) -> usize {{ 1 }}
 }}
 
 impl SocketSend for i16 {{
-    fn socket_send(&self, socket: &mut TcpStream) -> IoResult<()> {{
-        socket.write_i16::<BigEndian>(*self)
+    fn socket_send<W: Write + ?Sized>(&self, socket: &mut W, order: ByteOrder) -> IoResult<()> {{
+        match order {{
+            ByteOrder::BigEndian => socket.write_i16::<BigEndian>(*self),
+            ByteOrder::LittleEndian => socket.write_i16::<LittleEndian>(*self),
+        }}
     }}
+
+    fn wire_size(&self) -> usize {{ 2 }}
 }}
 
 impl SocketSend for u16 {{
-    fn socket_send(&self, socket: &mut TcpStream) -> IoResult<()> {{
-        socket.write_u16::<BigEndian>(*self)
+    fn socket_send<W: Write + ?Sized>(&self, socket: &mut W, order: ByteOrder) -> IoResult<()> {{
+        match order {{
+            ByteOrder::BigEndian => socket.write_u16::<BigEndian>(*self),
+            ByteOrder::LittleEndian => socket.write_u16::<LittleEndian>(*self),
+        }}
     }}
+
+    fn wire_size(&self) -> usize {{ 2 }}
 }}
 
 impl SocketSend for i32 {{
-    fn socket_send(&self, socket: &mut TcpStream) -> IoResult<()> {{
-        socket.write_i32::<BigEndian>(*self)
+    fn socket_send<W: Write + ?Sized>(&self, socket: &mut W, order: ByteOrder) -> IoResult<()> {{
+        match order {{
+            ByteOrder::BigEndian => socket.write_i32::<BigEndian>(*self),
+            ByteOrder::LittleEndian => socket.write_i32::<LittleEndian>(*self),
+        }}
     }}
+
+    fn wire_size(&self) -> usize {{ 4 }}
 }}
 
 impl SocketSend for u32 {{
-    fn socket_send(&self, socket: &mut TcpStream) -> IoResult<()> {{
-        socket.write_u32::<BigEndian>(*self)
+    fn socket_send<W: Write + ?Sized>(&self, socket: &mut W, order: ByteOrder) -> IoResult<()> {{
+        match order {{
+            ByteOrder::BigEndian => socket.write_u32::<BigEndian>(*self),
+            ByteOrder::LittleEndian => socket.write_u32::<LittleEndian>(*self),
+        }}
+    }}
+
+    fn wire_size(&self) -> usize {{ 4 }}
+}}
+
+impl SocketSend for u64 {{
+    fn socket_send<W: Write + ?Sized>(&self, socket: &mut W, order: ByteOrder) -> IoResult<()> {{
+        match order {{
+            ByteOrder::BigEndian => socket.write_u64::<BigEndian>(*self),
+            ByteOrder::LittleEndian => socket.write_u64::<LittleEndian>(*self),
+        }}
+    }}
+
+    fn wire_size(&self) -> usize {{ 8 }}
+}}
+
+impl SocketRecv for bool {{
+    fn socket_recv(cursor: &mut IoCursor<&[u8]>, _: ByteOrder) -> IoResult<bool> {{
+        Ok(cursor.read_u8()? != 0)
+    }}
+}}
+
+impl SocketRecv for i8 {{
+    fn socket_recv(cursor: &mut IoCursor<&[u8]>, _: ByteOrder) -> IoResult<i8> {{
+        cursor.read_i8()
+    }}
+}}
+
+impl SocketRecv for u8 {{
+    fn socket_recv(cursor: &mut IoCursor<&[u8]>, _: ByteOrder) -> IoResult<u8> {{
+        cursor.read_u8()
+    }}
+}}
+
+impl SocketRecv for i16 {{
+    fn socket_recv(cursor: &mut IoCursor<&[u8]>, order: ByteOrder) -> IoResult<i16> {{
+        match order {{
+            ByteOrder::BigEndian => cursor.read_i16::<BigEndian>(),
+            ByteOrder::LittleEndian => cursor.read_i16::<LittleEndian>(),
+        }}
+    }}
+}}
+
+impl SocketRecv for u16 {{
+    fn socket_recv(cursor: &mut IoCursor<&[u8]>, order: ByteOrder) -> IoResult<u16> {{
+        match order {{
+            ByteOrder::BigEndian => cursor.read_u16::<BigEndian>(),
+            ByteOrder::LittleEndian => cursor.read_u16::<LittleEndian>(),
+        }}
+    }}
+}}
+
+impl SocketRecv for i32 {{
+    fn socket_recv(cursor: &mut IoCursor<&[u8]>, order: ByteOrder) -> IoResult<i32> {{
+        match order {{
+            ByteOrder::BigEndian => cursor.read_i32::<BigEndian>(),
+            ByteOrder::LittleEndian => cursor.read_i32::<LittleEndian>(),
+        }}
+    }}
+}}
+
+impl SocketRecv for u32 {{
+    fn socket_recv(cursor: &mut IoCursor<&[u8]>, order: ByteOrder) -> IoResult<u32> {{
+        match order {{
+            ByteOrder::BigEndian => cursor.read_u32::<BigEndian>(),
+            ByteOrder::LittleEndian => cursor.read_u32::<LittleEndian>(),
+        }}
+    }}
+}}
+
+impl SocketRecv for u64 {{
+    fn socket_recv(cursor: &mut IoCursor<&[u8]>, order: ByteOrder) -> IoResult<u64> {{
+        match order {{
+            ByteOrder::BigEndian => cursor.read_u64::<BigEndian>(),
+            ByteOrder::LittleEndian => cursor.read_u64::<LittleEndian>(),
+        }}
     }}
 }}
 
@@ -118,6 +457,8 @@ enum Reply {{
     file.write_all(&parse_result.replies_list).unwrap();
     writeln!(&mut file, r#"
     Error(XError),
+    /// Reply that couldn't be decoded.
+    Invalid(IoError),
 }}
 
 enum ReplyType {{
@@ -126,68 +467,667 @@ enum ReplyType {{
     writeln!(&mut file, r#"
 }}
 
+impl ReplyType {{
+    /// Decodes a reply, made of a 32 bytes header followed by the number of additional 4 bytes
+    /// words indicated in the header.
+    fn decode(&self, packet: &[u8], order: ByteOrder) -> IoResult<Reply> {{
+        if packet.len() < 32 {{
+            return Err(IoError::new(ErrorKind::UnexpectedEof, "Truncated reply"));
+        }}
+
+        let length = u32::socket_recv(&mut IoCursor::new(&packet[4 .. 8]), order)? as usize;
+        if packet.len() < 32 + length * 4 {{
+            return Err(IoError::new(ErrorKind::UnexpectedEof, "Truncated reply"));
+        }}
+
+        let mut cursor = IoCursor::new(&packet[.. 32 + length * 4]);
+        Ok(match *self {{
+        "#).unwrap();
+    file.write_all(&parse_result.replies_decoders).unwrap();
+    writeln!(&mut file, r#"
+        }})
+    }}
+}}
+
+impl Reply {{
+    /// Returns true if no other reply follows this one for the same request.
+    fn is_last(&self) -> bool {{
+        match *self {{
+        "#).unwrap();
+    file.write_all(&parse_result.replies_last).unwrap();
+    writeln!(&mut file, r#"
+            _ => true
+        }}
+    }}
+
+    /// Returns true if the reply only marks the end of a series of replies and contains
+    /// nothing else.
+    fn is_end_marker(&self) -> bool {{
+        match *self {{
+        "#).unwrap();
+    file.write_all(&parse_result.replies_end_markers).unwrap();
+    writeln!(&mut file, r#"
+            _ => false
+        }}
+    }}
+}}
+
 pub enum Event {{
         "#).unwrap();
     file.write_all(&parse_result.events_list).unwrap();
     writeln!(&mut file, r#"
+    /// Error caused by a request whose reply nobody is waiting for.
+    Error(XError),
+    /// Generic event whose extension or type is unknown. `extension` is the major opcode of
+    /// the extension, and `data` contains the whole event.
+    Generic {{ extension: u8, evtype: u16, data: Vec<u8> }},
+    /// Event whose code is unknown, like the events of extensions that aren't generic events,
+    /// whose codes start at the `first_event` returned by `extension_info`. `data` contains
+    /// the 32 bytes of the event.
+    Unknown {{ code: u8, data: Vec<u8> }},
 }}
 
+impl Event {{
+    /// Decodes the 32 bytes of an event. Returns `Event::Unknown` if the code of the event is
+    /// unknown.
+    fn decode(packet: &[u8], order: ByteOrder) -> IoResult<Event> {{
+        if packet.len() < 32 {{
+            return Err(IoError::new(ErrorKind::UnexpectedEof, "Truncated event"));
+        }}
+
+        let mut cursor = IoCursor::new(&packet[.. 32]);
+
+        // the highest bit indicates whether the event was sent with `SendEvent`
+        Ok(match packet[0] & 0x7f {{
+        "#).unwrap();
+    file.write_all(&parse_result.events_decoders).unwrap();
+    writeln!(&mut file, r#"
+            code => Event::Unknown {{ code, data: packet[.. 32].to_vec() }},
+        }})
+    }}
+
+    /// Decodes a generic event, whose extension has been identified from its major opcode if
+    /// it has been queried. Returns `Event::Generic` if the extension or the type of the event
+    /// is unknown.
+    fn decode_generic(packet: &[u8], order: ByteOrder, extension: Option<&str>)
+                      -> IoResult<Event>
+    {{
+        if packet.len() < 32 {{
+            return Err(IoError::new(ErrorKind::UnexpectedEof, "Truncated event"));
+        }}
+
+        let event_type = u16::socket_recv(&mut IoCursor::new(&packet[8 .. 10]), order)?;
+        let mut cursor = IoCursor::new(packet);
+
+        Ok(match (extension, event_type) {{
+        "#).unwrap();
+    file.write_all(&parse_result.generic_events_decoders).unwrap();
+    writeln!(&mut file, r#"
+            (_, evtype) => Event::Generic {{ extension: packet[1], evtype, data: packet.to_vec() }},
+        }})
+    }}
+}}
+
+#[derive(Debug, Clone)]
 pub enum XError {{
         "#).unwrap();
     file.write_all(&parse_result.errors_list).unwrap();
     writeln!(&mut file, r#"
+    Unknown(UnknownError),
+}}
+
+/// An error whose code is unknown, for example an error of an extension that hasn't been
+/// queried.
+#[derive(Debug, Clone)]
+pub struct UnknownError {{
+    pub error_code: CARD8,
+    /// Sequence number of the request that caused the error.
+    pub sequence: u16,
+    pub bad_value: CARD32,
+    pub minor_opcode: CARD16,
+    pub major_opcode: CARD8,
+    /// Name of the request, unless its extension hasn't been queried.
+    pub request: Option<&'static str>,
+}}
+
+impl SocketRecv for UnknownError {{
+    fn socket_recv(cursor: &mut IoCursor<&[u8]>, order: ByteOrder) -> IoResult<UnknownError> {{
+        cursor.set_position(cursor.position() + 1);
+        let error_code = u8::socket_recv(cursor, order)?;
+        let sequence = u16::socket_recv(cursor, order)?;
+        let bad_value = u32::socket_recv(cursor, order)?;
+        let minor_opcode = u16::socket_recv(cursor, order)?;
+        let major_opcode = u8::socket_recv(cursor, order)?;
+        cursor.set_position(cursor.position() + 21);
+        Ok(UnknownError {{
+            error_code,
+            sequence,
+            bad_value,
+            minor_opcode,
+            major_opcode,
+            request: None,
+        }})
+    }}
+}}
+
+impl XError {{
+    /// Decodes the 32 bytes of an error.
+    ///
+    /// The errors of extensions are identified by the name of their extension and their number
+    /// relative to the first error of the extension, if it has been queried. The request is
+    /// named from its opcodes and the name of its extension, if it has been queried as well.
+    fn decode(packet: &[u8], order: ByteOrder, extension: Option<&str>, number: u8,
+              request_extension: Option<&str>) -> IoResult<XError>
+    {{
+        if packet.len() < 32 {{
+            return Err(IoError::new(ErrorKind::UnexpectedEof, "Truncated error"));
+        }}
+
+        let mut cursor = IoCursor::new(&packet[.. 32]);
+        let mut error = match (extension, number) {{
+        "#).unwrap();
+    file.write_all(&parse_result.errors_decoders).unwrap();
+    writeln!(&mut file, r#"
+            _ => XError::Unknown(UnknownError::socket_recv(&mut cursor, order)?)
+        }};
+
+        let (_, _, major, minor, _, _) = error.header();
+        *error.request_mut() = request_name(request_extension, major, minor);
+        Ok(error)
+    }}
+
+    /// Returns the name of the error, the sequence number, the major and minor opcodes, the
+    /// bad value and the name of the request.
+    fn header(&self) -> (&'static str, u16, u8, u16, Option<u32>, Option<&'static str>) {{
+        match *self {{
+        "#).unwrap();
+    file.write_all(&parse_result.errors_headers).unwrap();
+    writeln!(&mut file, r#"
+            XError::Unknown(ref e) => ("Unknown", e.sequence, e.major_opcode, e.minor_opcode,
+                                       Some(e.bad_value), e.request),
+        }}
+    }}
+
+    /// Returns the name of the request, to be filled in once the error is decoded.
+    fn request_mut(&mut self) -> &mut Option<&'static str> {{
+        match *self {{
+        "#).unwrap();
+    file.write_all(&parse_result.errors_requests).unwrap();
+    writeln!(&mut file, r#"
+            XError::Unknown(ref mut e) => &mut e.request,
+        }}
+    }}
+
+    /// Returns the lowest 16 bits of the sequence number of the request that caused the error.
+    pub fn sequence(&self) -> u16 {{
+        self.header().1
+    }}
+
+    /// Returns the major opcode of the request that caused the error.
+    pub fn major_opcode(&self) -> u8 {{
+        self.header().2
+    }}
+
+    /// Returns the minor opcode of the request that caused the error.
+    pub fn minor_opcode(&self) -> u16 {{
+        self.header().3
+    }}
+
+    /// Returns the invalid value (like a resource ID) if the error has one.
+    pub fn bad_value(&self) -> Option<u32> {{
+        self.header().4
+    }}
+
+    /// Returns the name of the request that caused the error, unless it is a request of an
+    /// extension that hasn't been queried.
+    pub fn request(&self) -> Option<&'static str> {{
+        self.header().5
+    }}
+}}
+
+impl ::std::fmt::Display for XError {{
+    fn fmt(&self, formatter: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {{
+        let (name, sequence, major, minor, bad_value, request) = self.header();
+
+        write!(formatter, "{{}} error in ", name)?;
+        match request {{
+            Some(request) => write!(formatter, "request {{}}", request)?,
+            None => write!(formatter, "request {{}}:{{}}", major, minor)?,
+        }}
+        write!(formatter, " (sequence number {{}}", sequence)?;
+        if let Some(value) = bad_value {{
+            write!(formatter, ", bad value {{}}", value)?;
+        }}
+        write!(formatter, ")")
+    }}
+}}
+
+impl ::std::error::Error for XError {{}}
+
+/// Returns the name of a request from the name of its extension, if it belongs to one, and its
+/// major and minor opcodes.
+fn request_name(extension: Option<&str>, major_opcode: u8, minor_opcode: u16)
+                -> Option<&'static str>
+{{
+    match (extension, major_opcode, minor_opcode) {{
+        "#).unwrap();
+    file.write_all(&parse_result.requests_names).unwrap();
+    writeln!(&mut file, r#"
+        _ => None
+    }}
 }}
 
 pub struct ReplyHandle<'a, T> {{
     connection: &'a XConnection,
-    sequence: u16,
-    get_reply: fn(Reply) -> Result<T, XError>,
+    sequence: u64,
+    get_reply: fn(Reply) -> IoResult<Result<T, XError>>,
 }}
 
-impl XConnection {{
+impl XConnection<TcpStream> {{
     /// Connects to an X server.
     ///
     /// Blocks until the server returns a success or an error.
-    pub fn connect<A>(address: A) -> XConnection where A: ToSocketAddrs {{
-        let connection = TcpStream::connect(address);
+    pub fn connect<A>(address: A) -> Result<XConnection<TcpStream>, ConnectError>
+                      where A: ToSocketAddrs
+    {{
+        let socket = TcpStream::connect(address)?;
+
+        // the display number is only used to find the credentials in the Xauthority file
+        let display = socket.peer_addr()?.port().wrapping_sub(6000);
+
+        XConnection::setup_connection(socket, display, 0)
     }}
+}}
 
-    /// Obtain an iterator for the events is the connection's queue.
-    pub fn events(&mut self) -> Events {{
-        Events {{
-            connection: self,
+#[cfg(unix)]
+impl XConnection<::std::os::unix::net::UnixStream> {{
+
+    /// Performs the handshake over an already connected socket, for example one that was
+    /// inherited from the parent process.
+    ///
+    /// # Safety
+    ///
+    /// The file descriptor must be a valid socket that is not used anywhere else. It is closed
+    /// when the connection is destroyed.
+    pub unsafe fn from_raw_fd(fd: ::std::os::unix::io::RawFd, byte_order: ByteOrder,
+                              auth_name: &[u8], auth_data: &[u8])
+                              -> Result<XConnection<::std::os::unix::net::UnixStream>, ConnectError>
+    {{
+        use std::os::unix::io::FromRawFd;
+        use std::os::unix::net::UnixStream;
+
+        // `UnixStream` only reads and writes the descriptor, so this works with any kind of socket
+        XConnection::from_stream(UnixStream::from_raw_fd(fd), byte_order, auth_name, auth_data)
+    }}
+}}
+
+impl<S> XConnection<S> where S: Stream + 'static {{
+    /// Performs the handshake over an already connected stream.
+    ///
+    /// The authorization protocol name and data are sent as they are. They can be empty if
+    /// the server doesn't require any authorization. All the numbers exchanged with the server
+    /// will use `byte_order`.
+    pub fn from_stream(mut stream: S, byte_order: ByteOrder, auth_name: &[u8], auth_data: &[u8])
+                       -> Result<XConnection<S>, ConnectError>
+    {{
+        let setup = XConnection::handshake(&mut stream, byte_order, auth_name, auth_data)?;
+        Ok(XConnection::new(stream, byte_order, setup, 0))
+    }}
+
+    /// Performs the handshake on a freshly opened socket, using the native byte order and the
+    /// credentials of the Xauthority file if there are any for this display.
+    fn setup_connection(mut socket: S, display: u16, default_screen: usize)
+                        -> Result<XConnection<S>, ConnectError>
+    {{
+        let byte_order = ByteOrder::native();
+        let (auth_name, auth_data) = auth::authorization(&socket, display);
+        let setup = XConnection::handshake(&mut socket, byte_order, &auth_name, &auth_data)?;
+        Ok(XConnection::new(socket, byte_order, setup, default_screen))
+    }}
+
+    /// Builds the connection once the handshake succeeded.
+    fn new(socket: S, byte_order: ByteOrder, setup: Setup, default_screen: usize)
+           -> XConnection<S>
+    {{
+        let xid = XidAllocator::new(setup.resource_id_base, setup.resource_id_mask);
+        #[cfg(unix)]
+        let raw_fd = socket.raw_fd();
+
+        XConnection {{
+            sequence: Mutex::new(SequenceTracker::new()),
+            read_buffer: Mutex::new(Vec::new()),
+            reader: Mutex::new(ReaderState::new()),
+            reader_done: Condvar::new(),
+            pending_events: Mutex::new(VecDeque::new()),
+            pending_answers: Mutex::new(Vec::new()),
+            waiting_for_answer: Mutex::new(Vec::new()),
+            setup,
+            default_screen,
+            byte_order,
+            extensions: Mutex::new(HashMap::new()),
+            xid: Mutex::new(xid),
+            maximum_request_length: Mutex::new(None),
+            tasks: Mutex::new(WaitingTasks::new()),
+            #[cfg(unix)]
+            raw_fd,
+            socket: Mutex::new(BufferedStream::new(socket)),
+        }}
+    }}
+}}
+
+/// Gives access to the methods of the connection, which don't depend on the type of the stream.
+impl<S> ::std::ops::Deref for XConnection<S> where S: Stream + 'static {{
+    type Target = XConnection;
+
+    fn deref(&self) -> &XConnection {{
+        self
+    }}
+}}
+
+impl XConnection {{
+
+    /// Sends the setup request and reads the answer of the server.
+    fn handshake(socket: &mut dyn Stream, order: ByteOrder, auth_name: &[u8], auth_data: &[u8])
+                 -> Result<Setup, ConnectError>
+    {{
+        // the lengths of the authorization name and data are sent on 16 bits
+        let auth_name_len = u16::try_from(auth_name.len()).map_err(|_| {{
+            IoError::new(ErrorKind::InvalidInput, "The authorization name is too long")
+        }})?;
+        let auth_data_len = u16::try_from(auth_data.len()).map_err(|_| {{
+            IoError::new(ErrorKind::InvalidInput, "The authorization data is too long")
+        }})?;
+
+        // the `SetupRequest` of the XML definitions doesn't pad the authorization name
+        // and data, so the request is written by hand
+        socket.write_u8(order.setup_byte())?;
+        socket.write_u8(0)?;
+        11u16.socket_send(socket, order)?;
+        0u16.socket_send(socket, order)?;
+        auth_name_len.socket_send(socket, order)?;
+        auth_data_len.socket_send(socket, order)?;
+        socket.write_all(&[0; 2])?;
+        socket.write_all(auth_name)?;
+        socket.write_all(&[0; 3][.. (4 - auth_name.len() % 4) % 4])?;
+        socket.write_all(auth_data)?;
+        socket.write_all(&[0; 3][.. (4 - auth_data.len() % 4) % 4])?;
+        socket.flush()?;
+
+        // the first 8 bytes contain the status and the length of the rest in 4-byte units
+        let mut packet = vec![0; 8];
+        socket.read_exact(&mut packet)?;
+        let status = packet[0];
+        let length = u16::socket_recv(&mut IoCursor::new(&packet[6 .. 8]), order)? as usize;
+        packet.resize(8 + length * 4, 0);
+        socket.read_exact(&mut packet[8 ..])?;
+
+        let mut cursor = IoCursor::new(&packet[..]);
+        match status {{
+            0 => Err(ConnectError::SetupFailed(SetupFailed::socket_recv(&mut cursor, order)?)),
+            1 => Ok(Setup::socket_recv(&mut cursor, order)?),
+            2 => Err(ConnectError::SetupAuthenticate(SetupAuthenticate::socket_recv(&mut cursor, order)?)),
+            _ => Err(ConnectError::IoError(invalid_value("status"))),
         }}
     }}
 
+    /// Returns the information sent by the server when the connection was established.
+    pub fn setup(&self) -> &Setup {{
+        &self.setup
+    }}
+
+    /// Returns the information about an extension, or `None` if the server doesn't support it.
+    ///
+    /// The server is only queried the first time for each extension.
+    pub fn extension_info(&self, name: &'static str) -> Option<QueryExtensionReply> {{
+        if let Some(info) = self.extensions.lock().unwrap().get(name) {{
+            return info.clone();
+        }}
+
+        let info = match self.QueryExtension_request(name.as_bytes()).map(|handle| handle.get()) {{
+            Ok(Ok(ref reply)) if reply.present => Some(reply.clone()),
+            Ok(Ok(_)) | Ok(Err(ReplyError::XError(_))) => None,
+            // the server will be queried again next time
+            Ok(Err(ReplyError::IoError(_))) | Err(_) => return None,
+        }};
+
+        self.extensions.lock().unwrap().insert(name, info.clone());
+        info
+    }}
+
+    /// Returns the maximum length of a request in bytes.
+    ///
+    /// The first call enables the BIG-REQUESTS extension if the server supports it, which
+    /// raises the limit of the core protocol.
+    pub fn maximum_request_length(&self) -> usize {{
+        let mut maximum = self.maximum_request_length.lock().unwrap();
+        if let Some(length) = *maximum {{
+            return length as usize * 4;
+        }}
+
+        let length = match self.BigRequestsEnable_request().map(|h| h.map(|handle| handle.get())) {{
+            Ok(Some(Ok(reply))) => reply.maximum_request_length,
+            Ok(None) | Ok(Some(Err(ReplyError::XError(_)))) => self.setup.maximum_request_length as u32,
+            // the extension will be enabled again next time
+            Ok(Some(Err(ReplyError::IoError(_)))) | Err(_) => {{
+                return self.setup.maximum_request_length as usize * 4;
+            }},
+        }};
+
+        *maximum = Some(length);
+        length as usize * 4
+    }}
+
+    /// Writes a GetInputFocus request, so that the server sends back a recent sequence number.
+    fn send_sync(socket: &mut BufferedStream, order: ByteOrder) -> IoResult<()> {{
+        socket.write_u8(43)?;
+        socket.write_u8(0)?;
+        1u16.socket_send(socket, order)
+    }}
+
+    /// Sends a GetInputFocus request whose reply is expected, and returns its sequence number.
+    fn sync(&self) -> IoResult<u64> {{
+        let mut socket = self.socket.lock().unwrap();
+        let mut sequence = self.sequence.lock().unwrap();
+
+        let seq = sequence.next(true);
+        self.waiting_for_answer.lock().unwrap().push((seq, Some(ReplyType::GetInputFocus)));
+        if let Err(err) = XConnection::send_sync(&mut *socket, self.byte_order) {{
+            self.waiting_for_answer.lock().unwrap().retain(|&(s, _)| s != seq);
+            return Err(err);
+        }}
+        Ok(seq)
+    }}
+
+    /// Checks that the server accepts a request of `size` bytes, and returns true if it must
+    /// use the length encoding of BIG-REQUESTS.
+    ///
+    /// BIG-REQUESTS is enabled if the request is larger than the limit of the core protocol.
+    fn check_request_size(&self, size: usize) -> IoResult<bool> {{
+        let size = size + (4 - size % 4) % 4;
+        if size <= self.setup.maximum_request_length as usize * 4 {{
+            return Ok(false);
+        }}
+
+        // the length of big requests takes 4 additional bytes
+        let big_request = size > 0xffff * 4;
+        let wire_size = if big_request {{ size + 4 }} else {{ size }};
+        if wire_size > self.maximum_request_length() {{
+            return Err(IoError::new(ErrorKind::InvalidInput, "The request is too large"));
+        }}
+
+        Ok(big_request)
+    }}
+
+    /// Returns the byte order used to communicate with the server.
+    pub fn byte_order(&self) -> ByteOrder {{
+        self.byte_order
+    }}
+
+    /// Returns the index in `setup().roots` of the screen to use by default.
+    pub fn default_screen(&self) -> usize {{
+        self.default_screen
+    }}
+
+    /// Sends the requests that are still in the output buffer.
+    ///
+    /// This is done automatically when waiting for a reply or an event.
+    pub fn flush(&self) -> IoResult<()> {{
+        self.socket.lock().unwrap().flush()
+    }}
+
         "#).unwrap();
     file.write_all(&parse_result.requests_list).unwrap();
     writeln!(&mut file, r#"
 }}
 
+impl<S> Drop for XConnection<S> where S: ?Sized + Stream {{
+    fn drop(&mut self) {{
+        let _ = self.socket.lock().unwrap().flush();
+    }}
+}}
+
 impl<'a, T> ReplyHandle<'a, T> {{
     /// Obtain the reply.
-    pub fn get(self) -> Result<T, XError> {{
-        while !self.is_ready() {{
-            self.connection.process_next();
-        }}
-
-        let mut pending = self.connection.pending_answers.lock();
-        let reply = pending.position(|&(seq, _)| seq == self.sequence).unwrap();
-        let reply = pending.remove(reply);
-        self.get_reply(reply)
+    ///
+    /// Blocks until the reply has been received. Fails if the connection to the server has
+    /// been lost or if the reply couldn't be decoded, as well as if the server returned an
+    /// error.
+    pub fn get(self) -> Result<T, ReplyError> {{
+        self.connection.process_until(|| self.is_ready())?;
+
+        let mut pending = self.connection.pending_answers.lock().unwrap();
+        let reply = pending.iter().position(|&(seq, _)| seq == self.sequence).unwrap();
+        let (_, reply) = pending.remove(reply);
+        Ok((self.get_reply)(reply)??)
     }}
 
     /// Returns true if the reply has been received.
     pub fn is_ready(&self) -> bool {{
-        let mut pending = self.connection.pending_answers.lock();
-        pending.find(|&(seq, _)| seq == self.sequence).is_some()
+        let pending = self.connection.pending_answers.lock().unwrap();
+        pending.iter().any(|&(seq, _)| seq == self.sequence)
     }}
 }}
 
 impl<'a, T> Drop for ReplyHandle<'a, T> {{
     fn drop(&mut self) {{
-        let mut pending = self.connection.pending_answers.lock();
-        let mut waiting = self.connection.waiting_for_answer.lock();
+        let mut pending = self.connection.pending_answers.lock().unwrap();
+        let mut waiting = self.connection.waiting_for_answer.lock().unwrap();
+
+        pending.retain(|&(seq, _)| seq != self.sequence);
+        waiting.retain(|&(seq, _)| seq != self.sequence);
+    }}
+}}
+
+/// Handle to a request without a reply, to find out whether it failed.
+pub struct VoidCookie<'a> {{
+    connection: &'a XConnection,
+    sequence: u64,
+    // sequence number of the request whose reply tells that the server is done with ours
+    sync: Option<u64>,
+}}
+
+impl<'a> VoidCookie<'a> {{
+    /// Waits until the server has processed the request, and returns the error that it caused
+    /// if there is one.
+    ///
+    /// Fails if the connection to the server has been lost.
+    pub fn check(mut self) -> IoResult<Result<(), XError>> {{
+        // the packets are received in the order of the requests, so the error, if any, has
+        // already been received if a packet of a later request has
+        if self.connection.sequence.lock().unwrap().last_received() <= self.sequence {{
+            self.sync = Some(self.connection.sync()?);
+        }}
+
+        let mut result = None;
+        self.connection.process_until(|| {{
+            let mut pending = self.connection.pending_answers.lock().unwrap();
+            if let Some(index) = pending.iter().position(|&(seq, _)| seq == self.sequence) {{
+                match pending.remove(index).1 {{
+                    Reply::Error(err) => result = Some(Ok(Err(err))),
+                    Reply::Invalid(err) => result = Some(Err(err)),
+                    _ => unreachable!()
+                }}
+                return true;
+            }}
+
+            match self.sync {{
+                Some(sync) if pending.iter().any(|&(seq, _)| seq == sync) => (),
+                Some(_) => return false,
+                None => (),
+            }}
+            result = Some(Ok(Ok(())));
+            true
+        }})?;
+
+        result.unwrap()
+    }}
+}}
+
+impl<'a> Drop for VoidCookie<'a> {{
+    fn drop(&mut self) {{
+        let mut pending = self.connection.pending_answers.lock().unwrap();
+        let mut waiting = self.connection.waiting_for_answer.lock().unwrap();
+
+        let sync = self.sync;
+        let sequence = self.sequence;
+        pending.retain(|&(seq, _)| seq != sequence && Some(seq) != sync);
+        waiting.retain(|&(seq, _)| seq != sequence && Some(seq) != sync);
+    }}
+}}
+
+/// Handle to the series of replies of a request like `ListFontsWithInfo`.
+///
+/// Iterating blocks until the next reply is received, and stops after the last reply or
+/// after an error. Replies that can't be decoded are returned as an `IoError`, as well as the
+/// error of receiving from the server if the connection has been lost.
+pub struct MultiReplyHandle<'a, T> {{
+    connection: &'a XConnection,
+    sequence: u64,
+    get_reply: fn(Reply) -> IoResult<Result<T, XError>>,
+    // true once the last reply or an error has been returned
+    finished: bool,
+}}
+
+impl<'a, T> Iterator for MultiReplyHandle<'a, T> {{
+    type Item = Result<T, ReplyError>;
+
+    fn next(&mut self) -> Option<Result<T, ReplyError>> {{
+        if self.finished {{
+            return None;
+        }}
+
+        let mut reply = None;
+        let received = self.connection.process_until(|| {{
+            let mut pending = self.connection.pending_answers.lock().unwrap();
+            reply = pending.iter().position(|&(seq, _)| seq == self.sequence)
+                           .map(|index| pending.remove(index).1);
+            reply.is_some()
+        }});
+
+        if let Err(err) = received {{
+            self.finished = true;
+            return Some(Err(ReplyError::IoError(err)));
+        }}
+
+        let reply = reply.unwrap();
+        if reply.is_last() {{
+            self.finished = true;
+            if reply.is_end_marker() {{
+                return None;
+            }}
+        }}
+
+        Some(match (self.get_reply)(reply) {{
+            Ok(reply) => reply.map_err(ReplyError::XError),
+            Err(err) => Err(ReplyError::IoError(err)),
+        }})
+    }}
+}}
+
+impl<'a, T> Drop for MultiReplyHandle<'a, T> {{
+    fn drop(&mut self) {{
+        let mut pending = self.connection.pending_answers.lock().unwrap();
+        let mut waiting = self.connection.waiting_for_answer.lock().unwrap();
 
         pending.retain(|&(seq, _)| seq != self.sequence);
         waiting.retain(|&(seq, _)| seq != self.sequence);
@@ -200,72 +1140,216 @@ struct ParseResult {
     typedefs: Vec<u8>,
     replies_list: Vec<u8>,
     replies_types: Vec<u8>,
+    /// Match arms that decode each `ReplyType` into a `Reply`.
+    replies_decoders: Vec<u8>,
+    /// Match arms that tell whether a reply is the last one of its request.
+    replies_last: Vec<u8>,
+    /// Match arms that tell whether a reply only marks the end of a series of replies.
+    replies_end_markers: Vec<u8>,
     events_list: Vec<u8>,
+    /// Match arms that decode each event from its number.
+    events_decoders: Vec<u8>,
+    /// Match arms that decode each generic event from the name of its extension and its number.
+    generic_events_decoders: Vec<u8>,
+    /// Names of the generic events, which are decoded differently from the other events.
+    generic_events: Vec<String>,
     errors_list: Vec<u8>,
+    /// Match arms that decode each error from the name of its extension and its number.
+    errors_decoders: Vec<u8>,
+    /// Match arms that return the common fields of each error.
+    errors_headers: Vec<u8>,
+    /// Match arms that return the name of the request of each error, to be filled in.
+    errors_requests: Vec<u8>,
+    /// Match arms that return the name of each request from the name of its extension and its
+    /// opcodes.
+    requests_names: Vec<u8>,
     requests_list: Vec<u8>,
+    /// List of (field type, enum name) for the fields with an `altenum` attribute.
+    altenums: Vec<(String, String)>,
+    /// The `extension-xname` and `extension-name` of the file being parsed, if it describes
+    /// an extension. The requests of extensions are prefixed with the latter.
+    extension: Option<(String, String)>,
+    types: TypesInfo,
+}
+
+/// Informations about the types that have been parsed so far.
+pub struct TypesInfo {
+    /// Names of all the `<xidtype>`s.
+    pub xidtypes: Vec<String>,
+    /// List of (item name, value) of each `<enum>`.
+    pub enums: HashMap<String, Vec<(String, u32)>>,
+    /// Names of the parameters required to decode the structs that use `<paramref>`.
+    pub struct_params: HashMap<String, Vec<String>>,
+    /// Number of bytes on the wire of the types that have a fixed size.
+    pub sizes: HashMap<String, usize>,
+    /// Names of the `<error>`s that have a `bad_value` field.
+    pub errors_with_bad_value: Vec<String>,
+    /// Names of all the types defined so far.
+    pub names: HashSet<String>,
+    /// Types of the header being parsed whose name was already taken, with their new name.
+    pub renames: HashMap<String, String>,
+}
+
+impl TypesInfo {
+    /// Returns the value of an item of an enum.
+    pub fn enum_item(&self, enum_name: &str, item: &str) -> u32 {
+        self.enums.get(enum_name).and_then(|items| {
+            items.iter().find(|&(i, _)| i == item).map(|&(_, value)| value)
+        }).unwrap_or_else(|| panic!("Unknown enum item {}::{}", enum_name, item))
+    }
+}
+
+fn primitive_sizes() -> HashMap<String, usize> {
+    let mut sizes = HashMap::new();
+    for &(ty, size) in [("u8", 1), ("f32", 4), ("f64", 8), ("BYTE", 1), ("INT8", 1), ("INT16", 2),
+                        ("INT32", 4), ("CARD8", 1), ("CARD16", 2), ("CARD32", 4), ("CARD64", 8),
+                        ("BOOL", 1)]
+                       .iter()
+    {
+        sizes.insert(ty.to_string(), size);
+    }
+    sizes
 }
 
 fn parse<R>(parse: &mut ParseResult, input: R) where R: Read {
     let mut events = EventReader::new(input);
 
-    match recv(&mut events) {
-        XmlEvent::StartElement{ref name, ..} if name.local_name == "xcb" => (),
+    parse.extension = match recv(&mut events) {
+        XmlEvent::StartElement{ref name, ref attributes, ..} if name.local_name == "xcb" => {
+            get_attribute(attributes, "extension-xname")
+                .map(|xname| (xname, get_attribute(attributes, "extension-name").unwrap()))
+        },
         msg => panic!("Expected `<xcb>`, found: {:?}", msg),
     };
+    parse.types.renames.clear();
+
+    loop {
+        match recv(&mut events) {
+            // `<struct>`
+            XmlEvent::StartElement{ref name, ref attributes, ..}
+                if name.local_name == "struct" =>
+            {
+                let struct_name = get_attribute(attributes, "name").unwrap();
+                let struct_name = type_name(parse, &struct_name);
+                parse_struct(parse, &mut events, &struct_name);
+            },
+
+            // `<request>`
+            XmlEvent::StartElement{ref name, ref attributes, ..}
+                if name.local_name == "request" =>
+            {
+                let name = get_attribute(attributes, "name").unwrap();
+                let name = match parse.extension {
+                    Some((_, ref prefix)) => format!("{}{}", prefix, name),
+                    None => name
+                };
+                let opcode = get_attribute(attributes, "opcode").unwrap().parse().unwrap();
+                parse_request(parse, &mut events, &name, opcode);
+            },
+
+            // `<typedef>`
+            XmlEvent::StartElement{ref name, ref attributes, ..}
+                if name.local_name == "typedef" =>
+            {
+                let oldname = get_attribute(attributes, "oldname").unwrap();
+                let newname = get_attribute(attributes, "newname").unwrap();
+                let newname = type_name(parse, &newname);
+                writeln!(parse.typedefs, "pub type {} = {};", newname, oldname).unwrap();
+
+                if let Some(size) = parse.types.sizes.get(&oldname).cloned() {
+                    parse.types.sizes.insert(newname, size);
+                }
+            },
+            XmlEvent::EndElement{ref name, ..} if name.local_name == "typedef" => {
+            },
+
+            // `<xidtype>`
+            XmlEvent::StartElement{ref name, ref attributes, ..}
+                if name.local_name == "xidtype" =>
+            {
+                let name = get_attribute(attributes, "name").unwrap();
+                let name = type_name(parse, &name);
+                write_xidtype(parse, &name);
+            },
+            XmlEvent::EndElement{ref name, ..} if name.local_name == "xidtype" => {
+            },
+
+            // `<xidunion>`
+            XmlEvent::StartElement{ref name, ref attributes, ..}
+                if name.local_name == "xidunion" =>
+            {
+                let name = get_attribute(attributes, "name").unwrap();
+                let name = type_name(parse, &name);
+                write_xidtype(parse, &name);
+                parse_xidunion(parse, &mut events, &name);
+            },
+
+            // `<event>`
+            XmlEvent::StartElement{ref name, ref attributes, ..}
+                if name.local_name == "event" =>
+            {
+                let name = event_name(parse, &get_attribute(attributes, "name").unwrap());
+                let number = get_attribute(attributes, "number").unwrap().parse().unwrap();
+                let no_sequence_number = get_attribute(attributes, "no-sequence-number")
+                                                .map(|v| v == "true").unwrap_or(false);
+                let generic = get_attribute(attributes, "xge").map(|v| v == "true")
+                                                               .unwrap_or(false);
+                parse_event(parse, &mut events, &name, number, no_sequence_number, generic);
+            },
 
-    loop {
-        match recv(&mut events) {
-            // `<struct>`
+            // `<eventcopy>`
             XmlEvent::StartElement{ref name, ref attributes, ..}
-                if name.local_name == "struct" =>
+                if name.local_name == "eventcopy" =>
             {
-                let struct_name = get_attribute(attributes, "name").unwrap();
-                parse_struct(parse, &mut events, &struct_name);
+                let name = event_name(parse, &get_attribute(attributes, "name").unwrap());
+                let number = get_attribute(attributes, "number").unwrap().parse().unwrap();
+                let reference = event_name(parse, &get_attribute(attributes, "ref").unwrap());
+                skip_element(&mut events);
+
+                writeln!(parse.typedefs, "pub type {}Event = {}Event;", name, reference).unwrap();
+                let generic = parse.generic_events.contains(&reference);
+                write_event_variant(parse, &name, number, generic);
             },
 
-            // `<request>`
+            // `<error>`
             XmlEvent::StartElement{ref name, ref attributes, ..}
-                if name.local_name == "request" =>
+                if name.local_name == "error" =>
             {
                 let name = get_attribute(attributes, "name").unwrap();
-                let opcode = get_attribute(attributes, "opcode").unwrap().parse().unwrap();
-                parse_request(parse, &mut events, &name, opcode);
+                let number = get_attribute(attributes, "number").unwrap().parse().unwrap();
+                parse_error(parse, &mut events, &name, number);
             },
 
-            // `<typedef>`
+            // `<errorcopy>`
             XmlEvent::StartElement{ref name, ref attributes, ..}
-                if name.local_name == "typedef" =>
+                if name.local_name == "errorcopy" =>
             {
-                let oldname = get_attribute(attributes, "oldname").unwrap();
-                let newname = get_attribute(attributes, "newname").unwrap();
-                writeln!(parse.typedefs, "pub type {} = {};", newname, oldname).unwrap();
-            },
-            XmlEvent::EndElement{ref name, ..} if name.local_name == "typedef" => {
+                let name = get_attribute(attributes, "name").unwrap();
+                let number = get_attribute(attributes, "number").unwrap().parse().unwrap();
+                let reference = get_attribute(attributes, "ref").unwrap();
+                skip_element(&mut events);
+
+                writeln!(parse.typedefs, "pub type {}Error = {}Error;", name, reference).unwrap();
+                let has_bad_value = parse.types.errors_with_bad_value.contains(&reference);
+                write_error_variant(parse, &name, number, has_bad_value);
             },
 
-            // `<xidtype>`
+            // `<union>`
             XmlEvent::StartElement{ref name, ref attributes, ..}
-                if name.local_name == "xidtype" =>
+                if name.local_name == "union" =>
             {
                 let name = get_attribute(attributes, "name").unwrap();
-                writeln!(parse.typedefs, "pub struct {}(pub u32);", name).unwrap();
-                writeln!(parse.typedefs, r#"
-                    impl SocketSend for {} {{
-                        fn socket_send(&self, socket: &mut TcpStream) -> IoResult<()> {{
-                            socket.write_u32::<BigEndian>(self.0)
-                        }}
-                    }}"#, name).unwrap();
-            },
-            XmlEvent::EndElement{ref name, ..} if name.local_name == "xidtype" => {
+                let name = type_name(parse, &name);
+                parse_union(parse, &mut events, &name);
             },
 
-            // `<event>`
+            // `<enum>`
             XmlEvent::StartElement{ref name, ref attributes, ..}
-                if name.local_name == "event" =>
+                if name.local_name == "enum" =>
             {
                 let name = get_attribute(attributes, "name").unwrap();
-                let number = get_attribute(attributes, "number").unwrap().parse().unwrap();
-                parse_event(parse, &mut events, &name, number);
+                let name = type_name(parse, &name);
+                parse_enum(parse, &mut events, &name);
             },
 
             // we ignore `<import />` as it's C-specific
@@ -278,24 +1362,113 @@ fn parse<R>(parse: &mut ParseResult, input: R) where R: Read {
             XmlEvent::EndElement{ref name} if name.local_name == "xcb" => break,
 
             // error handling
-            msg => ()// FIXME: panic!("Unexpected {:?}", msg),
+            _msg => ()// FIXME: panic!("Unexpected {:?}", msg),
+        }
+    }
+}
+
+/// Returns the name of the Rust type of a type defined by the header being parsed.
+///
+/// All the types end up in the same module, so the types of extensions whose name is already
+/// taken are prefixed with the name of the extension.
+fn type_name(parse: &mut ParseResult, name: &str) -> String {
+    let rust_name = match parse.extension {
+        Some((_, ref prefix)) if parse.types.names.contains(name) => format!("{}{}", prefix, name),
+        _ => name.to_string()
+    };
+
+    if rust_name != name {
+        parse.types.renames.insert(name.to_string(), rust_name.clone());
+    }
+    parse.types.names.insert(rust_name.clone());
+    rust_name
+}
+
+/// Returns the name of the variant of `Event` of an event defined by the header being parsed.
+/// The events of extensions are prefixed with the name of the extension.
+fn event_name(parse: &ParseResult, name: &str) -> String {
+    match parse.extension {
+        Some((_, ref prefix)) => format!("{}{}", prefix, name),
+        None => name.to_string()
+    }
+}
+
+/// Writes the definition of a type that holds an XID.
+fn write_xidtype(parse: &mut ParseResult, name: &str) {
+    writeln!(parse.typedefs, "#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]").unwrap();
+    writeln!(parse.typedefs, "pub struct {}(pub u32);", name).unwrap();
+    parse.types.xidtypes.push(name.to_string());
+    parse.types.sizes.insert(name.to_string(), 4);
+    writeln!(parse.typedefs, r#"
+        impl SocketSend for {name} {{
+            fn socket_send<W: Write + ?Sized>(&self, socket: &mut W, order: ByteOrder) -> IoResult<()> {{
+                self.0.socket_send(socket, order)
+            }}
+
+            fn wire_size(&self) -> usize {{ 4 }}
+        }}
+
+        impl SocketRecv for {name} {{
+            fn socket_recv(cursor: &mut IoCursor<&[u8]>, order: ByteOrder) -> IoResult<{name}> {{
+                Ok({name}(u32::socket_recv(cursor, order)?))
+            }}
+        }}"#, name = name).unwrap();
+}
+
+/// Parses the `<type>` elements of a `<xidunion>`, and writes the conversions from these types.
+fn parse_xidunion<R>(parse: &mut ParseResult, events: &mut EventReader<R>, union_name: &str)
+                     where R: Read
+{
+    loop {
+        match recv(events) {
+            XmlEvent::EndElement{ref name} if name.local_name == "xidunion" => break,
+
+            // `<type>`
+            XmlEvent::StartElement{ref name, ..} if name.local_name == "type" => {
+                let ty = match recv(events) {
+                    XmlEvent::Characters(ty) => ty.trim().to_string(),
+                    msg => panic!("Expected a type name, found: {:?}", msg),
+                };
+                skip_element(events);
+
+                writeln!(parse.typedefs, r#"
+                    impl From<{ty}> for {union} {{
+                        fn from(value: {ty}) -> {union} {{
+                            {union}(value.0)
+                        }}
+                    }}"#, ty = ty, union = union_name).unwrap();
+            },
+
+            XmlEvent::StartElement{..} => skip_element(events),
+            _ => ()
         }
     }
 }
 
 fn recv<R>(events: &mut EventReader<R>) -> XmlEvent where R: Read {
-    for event in events.events() {
-        match event {
-            XmlEvent::StartDocument{..} => (),
-            XmlEvent::Comment(_) => (),
-            XmlEvent::Whitespace(_) => (),
-            XmlEvent::EndDocument => panic!("The end of the document has been reached"),
-            XmlEvent::Error(err) => panic!("XML error: {:?}", err),
-            event => return event,
+    loop {
+        match events.next() {
+            Ok(XmlEvent::StartDocument{..}) => (),
+            Ok(XmlEvent::Comment(_)) => (),
+            Ok(XmlEvent::Whitespace(_)) => (),
+            Ok(XmlEvent::EndDocument) => panic!("The end of the document has been reached"),
+            Ok(event) => return event,
+            Err(err) => panic!("XML error: {}", err),
         }
     }
+}
+
+/// Skips everything until the end of the element whose start has just been read.
+fn skip_element<R>(events: &mut EventReader<R>) where R: Read {
+    let mut depth = 1;
 
-    unreachable!()
+    while depth != 0 {
+        match recv(events) {
+            XmlEvent::StartElement{..} => depth += 1,
+            XmlEvent::EndElement{..} => depth -= 1,
+            _ => ()
+        }
+    }
 }
 
 fn get_attribute(a: &[xml::attribute::OwnedAttribute], name: &str) -> Option<String> {
@@ -305,7 +1478,8 @@ fn get_attribute(a: &[xml::attribute::OwnedAttribute], name: &str) -> Option<Str
 fn parse_struct<R>(parse: &mut ParseResult, events: &mut EventReader<R>, struct_name: &str)
                    where R: Read
 {
-    let mut request_struct_parser = StructContentParser::new(struct_name, StructType::Struct);
+    let mut request_struct_parser = StructContentParser::new(struct_name, StructType::Struct,
+                                                             &parse.types.renames);
 
     loop {
         match recv(events) {
@@ -314,20 +1488,35 @@ fn parse_struct<R>(parse: &mut ParseResult, events: &mut EventReader<R>, struct_
         }
     }
 
-    request_struct_parser.finish(&mut parse.typedefs);
+    parse.altenums.extend(request_struct_parser.alternate_enums().iter().cloned());
+    let mut switches = Vec::new();
+    request_struct_parser.finish(&mut parse.typedefs, &mut switches, &mut parse.types);
+    parse.typedefs.write_all(&switches).unwrap();
 }
 
 fn parse_request<R>(parse: &mut ParseResult, events: &mut EventReader<R>,
                     name: &str, opcode: u8) where R: Read
 {
     let mut function_body = Vec::new();
-    writeln!(function_body, r#"
-        let mut socket = self.socket.lock().unwrap();
-        let seq = self.sequence.fetch_add(1, Ordering::Relaxed) as u32;"#).unwrap();
+
+    // the major opcode of an extension is obtained from the server
+    let extension = parse.extension.as_ref().map(|(xname, _)| xname.clone());
+    if let Some(ref xname) = extension {
+        writeln!(function_body, r#"
+        let major_opcode = match self.extension_info("{}") {{
+            Some(info) => info.major_opcode,
+            None => return Ok(None)
+        }};"#, xname).unwrap();
+    }
 
     let mut docs = Vec::new();
+    let mut reply = None;
 
-    let mut request_struct_parser = StructContentParser::new("Request", StructType::Request { opcode: opcode });
+    let mut request_struct_parser = StructContentParser::new("Request", StructType::Request {
+        name: name.to_string(),
+        opcode,
+        extension: extension.is_some(),
+    }, &parse.types.renames);
 
     loop {
         match recv(events) {
@@ -340,49 +1529,251 @@ fn parse_request<R>(parse: &mut ParseResult, events: &mut EventReader<R>,
                 parse_doc(&mut docs, events);
             },
 
+            // `<reply>`
+            XmlEvent::StartElement{name: ref elem_name, ..}
+                if elem_name.local_name == "reply" =>
+            {
+                reply = Some(parse_reply(parse, events, name));
+            },
+
             ev => request_struct_parser.feed(ev, events),
         }
     }
 
-    let fields = request_struct_parser.finish(&mut function_body);
+    match extension {
+        None => writeln!(parse.requests_names, "        (None, {}, _) => Some(\"{}\"),",
+                         opcode, name).unwrap(),
+        Some(ref xname) => writeln!(parse.requests_names, "        (Some(\"{}\"), _, {}) => \
+                                                           Some(\"{}\"),",
+                                    xname, opcode, name).unwrap(),
+    }
+
+    parse.altenums.extend(request_struct_parser.alternate_enums().iter().cloned());
+    let fields = request_struct_parser.finish(&mut function_body, &mut parse.typedefs,
+                                              &mut parse.types);
 
-    let mut request_function = Vec::new();
     let mut struct_construction = Vec::new();
-    write!(request_function, "pub fn {}_request(&mut self", name).unwrap();
-    for (name, ty) in fields {
-        write!(&mut request_function, ", {}: {}", name, ty).unwrap();
-        write!(&mut struct_construction, "{}: {},", name, name);
+    for (name, _) in fields.iter() {
+        write!(&mut struct_construction, "{},", name).unwrap();
     }
 
-    if struct_construction.is_empty() {
-        write!(function_body, "Request {{ e: () }}").unwrap();
-    } else {
-        write!(function_body, "Request {{").unwrap();
-        function_body.write_all(&struct_construction).unwrap();
-        write!(function_body, "}}").unwrap();
-    }
+    write!(function_body, "let request = Request {{").unwrap();
+    function_body.write_all(&struct_construction).unwrap();
+    write!(function_body, "}};").unwrap();
+
+    // BIG-REQUESTS may have to be enabled, which requires the socket, and the size must be
+    // checked before a sequence number is used
     writeln!(function_body, r#"
-        .send(&mut socket, seq);
+        let big_request = self.check_request_size(request.wire_size())?;
 
-        fn get_reply(_: Reply) -> Result<(), XError> {{
-            Ok(())
-        }}
+        let mut socket = self.socket.lock().unwrap();
+        let mut sequence = self.sequence.lock().unwrap();"#).unwrap();
 
-        ReplyHandle {{
-            connection: self,
-            sequence: seq & 0xffff,
-            get_reply: get_reply,
+    let send_args = if extension.is_some() {
+        "&mut *socket, self.byte_order, major_opcode, big_request"
+    } else {
+        "&mut *socket, self.byte_order, big_request"
+    };
+    let send = format!(r#"
+        request.send({})?;"#, send_args);
+
+    // if the request can't be sent, nothing will be received for it
+    let send_expected = format!(r#"
+        if let Err(err) = request.send({}) {{
+            self.waiting_for_answer.lock().unwrap().retain(|&(s, _)| s != seq);
+            return Err(err);
+        }}"#, send_args);
+
+    let reply = match reply {
+        Some(reply) => reply,
+        None => {
+            // the server only sends the lowest 16 bits of the sequence numbers, which are
+            // rebuilt from the sequence number of the previous reply
+            writeln!(function_body, r#"
+        if sequence.needs_sync() {{
+            sequence.next(true);
+            XConnection::send_sync(&mut *socket, self.byte_order)?;
         }}"#).unwrap();
 
-    parse.requests_list.write_all(&docs).unwrap();
-    writeln!(parse.requests_list, "").unwrap();
-    parse.requests_list.write_all(&request_function).unwrap();
-    write!(parse.requests_list, ") -> ReplyHandle<").unwrap();
-    write!(parse.requests_list, "()").unwrap();
-    writeln!(parse.requests_list, "> {{").unwrap();
-    parse.requests_list.write_all(&function_body).unwrap();
-    writeln!(parse.requests_list, "}}").unwrap();
-    writeln!(parse.requests_list, "").unwrap();
+            // the errors of unchecked requests end up in the events queue
+            let mut unchecked_body = function_body.clone();
+            writeln!(unchecked_body, r#"
+        sequence.next(false);"#).unwrap();
+            writeln!(unchecked_body, "{}", send).unwrap();
+            write_request_function(parse, &docs, extension.as_ref(), name, &fields,
+                                   RequestFunction {
+                                       suffix: "",
+                                       return_type: "()",
+                                       value: "()",
+                                       body: &unchecked_body,
+                                       extra_docs: "/// Errors are received as `Event::Error`.",
+                                   });
+
+            // the error of checked requests is expected like a reply
+            let mut checked_body = function_body;
+            writeln!(checked_body, r#"
+        let seq = sequence.next(false);
+        self.waiting_for_answer.lock().unwrap().push((seq, None));"#).unwrap();
+            writeln!(checked_body, "{}", send_expected).unwrap();
+            write_request_function(parse, &docs, extension.as_ref(), name, &fields,
+                                   RequestFunction {
+                                       suffix: "_checked",
+                                       return_type: "VoidCookie<'_>",
+                                       value: r#"
+        VoidCookie {
+            connection: self,
+            sequence: seq,
+            sync: None,
+        }"#,
+                                       body: &checked_body,
+                                       extra_docs: "/// Errors are returned by `VoidCookie::check`.",
+                                   });
+            return;
+        },
+    };
+
+    // the reply must be expected before the request is sent
+    writeln!(function_body, r#"
+        let seq = sequence.next(true);
+        self.waiting_for_answer.lock().unwrap().push((seq, Some(ReplyType::{})));"#, name)
+            .unwrap();
+    writeln!(function_body, "{}", send_expected).unwrap();
+
+    writeln!(function_body, r#"
+        fn get_reply(reply: Reply) -> IoResult<Result<{reply}, XError>> {{
+            match reply {{
+                Reply::{name}(reply) => Ok(Ok(reply)),
+                Reply::Error(err) => Ok(Err(err)),
+                Reply::Invalid(err) => Err(err),
+                _ => unreachable!()
+            }}
+        }}"#, reply = reply, name = name).unwrap();
+
+    let multi_reply = MULTI_REPLY_REQUESTS.iter().find(|&&(request, _, _)| request == name);
+    let (handle, handle_type) = match multi_reply {
+        Some(&(_, last, end_marker)) => {
+            writeln!(parse.replies_last, "            Reply::{}(ref reply) => {},", name, last)
+                .unwrap();
+            if end_marker {
+                writeln!(parse.replies_end_markers, "            Reply::{}(ref reply) => {},",
+                         name, last).unwrap();
+            }
+
+            (r#"
+        MultiReplyHandle {
+            connection: self,
+            sequence: seq,
+            get_reply,
+            finished: false,
+        }"#, format!("MultiReplyHandle<'_, {}>", reply))
+        },
+
+        None => (r#"
+        ReplyHandle {
+            connection: self,
+            sequence: seq,
+            get_reply,
+        }"#, format!("ReplyHandle<'_, {}>", reply)),
+    };
+
+    write_request_function(parse, &docs, extension.as_ref(), name, &fields,
+                           RequestFunction {
+                               suffix: "",
+                               return_type: &handle_type,
+                               value: handle,
+                               body: &function_body,
+                               extra_docs: "",
+                           });
+}
+
+/// One of the methods of `XConnection` that send a request.
+struct RequestFunction<'a> {
+    /// Added to the `<name>_request` name of the method.
+    suffix: &'a str,
+    return_type: &'a str,
+    /// The value returned by the method after running `body`.
+    value: &'a str,
+    body: &'a [u8],
+    /// Documentation added after the documentation of the request.
+    extra_docs: &'a str,
+}
+
+/// Writes a `<name>_request<suffix>` method of `XConnection` whose parameters are the fields of
+/// the request, or that returns `Ok(None)` if the extension of the request isn't supported.
+fn write_request_function(parse: &mut ParseResult, docs: &[u8], extension: Option<&String>,
+                          name: &str, fields: &[(String, String)], function: RequestFunction)
+{
+    let dest = &mut parse.requests_list;
+    let RequestFunction { suffix, return_type, value, body, extra_docs } = function;
+
+    let mut notes = Vec::new();
+    if !extra_docs.is_empty() {
+        notes.push(extra_docs.to_string());
+    }
+    if let Some(xname) = extension {
+        notes.push(format!("/// Returns `Ok(None)` if the server doesn't support the {} extension.",
+                           xname));
+    }
+
+    // the docs are a single line, without the line break
+    dest.write_all(docs).unwrap();
+    writeln!(dest).unwrap();
+    for (index, note) in notes.iter().enumerate() {
+        if index > 0 || !docs.is_empty() {
+            writeln!(dest, "///").unwrap();
+        }
+        writeln!(dest, "{}", note).unwrap();
+    }
+
+    // the names of the methods follow the names of the requests in the XML definitions
+    writeln!(dest, "#[allow(non_snake_case)]").unwrap();
+    if fields.len() > 6 {
+        writeln!(dest, "#[allow(clippy::too_many_arguments)]").unwrap();
+    }
+    write!(dest, "pub fn {}_request{}(&self", name, suffix).unwrap();
+    for (name, ty) in fields.iter() {
+        write!(dest, ", {}: {}", name, ty).unwrap();
+    }
+    match extension {
+        Some(_) => writeln!(dest, ") -> IoResult<Option<{}>> {{", return_type).unwrap(),
+        None => writeln!(dest, ") -> IoResult<{}> {{", return_type).unwrap(),
+    };
+    dest.write_all(body).unwrap();
+    match extension {
+        Some(_) => writeln!(dest, "Ok(Some({}))", value).unwrap(),
+        None => writeln!(dest, "Ok({})", value).unwrap(),
+    };
+    writeln!(dest, "}}").unwrap();
+    writeln!(dest).unwrap();
+}
+
+/// Parses the `<reply>` of a request, and returns the name of the generated struct.
+fn parse_reply<R>(parse: &mut ParseResult, events: &mut EventReader<R>, request_name: &str)
+                  -> String where R: Read
+{
+    let reply_name = format!("{}Reply", request_name);
+    let mut reply_struct_parser = StructContentParser::new(&reply_name, StructType::Reply,
+                                                           &parse.types.renames);
+
+    loop {
+        match recv(events) {
+            XmlEvent::EndElement{ref name} if name.local_name == "reply" => break,
+            ev => reply_struct_parser.feed(ev, events),
+        }
+    }
+
+    parse.altenums.extend(reply_struct_parser.alternate_enums().iter().cloned());
+    let mut switches = Vec::new();
+    reply_struct_parser.finish(&mut parse.typedefs, &mut switches, &mut parse.types);
+    parse.typedefs.write_all(&switches).unwrap();
+
+    writeln!(parse.replies_list, "    {}({}),", request_name, reply_name).unwrap();
+    writeln!(parse.replies_types, "    {},", request_name).unwrap();
+    writeln!(parse.replies_decoders, "            ReplyType::{name} => \
+                                      Reply::{name}({reply}::socket_recv(&mut cursor, order)?),",
+             name = request_name, reply = reply_name).unwrap();
+
+    reply_name
 }
 
 fn parse_doc<R, W>(output: &mut W, events: &mut EventReader<R>)
@@ -402,36 +1793,436 @@ fn parse_doc<R, W>(output: &mut W, events: &mut EventReader<R>)
                 write!(output, "/// ").unwrap();
             },
 
-            msg => ()// FIXME: panic!("Unexpected {:?}", msg),
+            _msg => ()// FIXME: panic!("Unexpected {:?}", msg),
         }
     }
 }
 
-fn parse_event<R>(output: &mut ParseResult, events: &mut EventReader<R>, name: &str, number: u8)
-                  where R: Read
+fn parse_event<R>(parse: &mut ParseResult, events: &mut EventReader<R>, name: &str, number: u8,
+                  no_sequence_number: bool, generic: bool) where R: Read
 {
     let mut docs = Vec::new();
 
+    let event_name = format!("{}Event", name);
+    let struct_type = if generic {
+        parse.generic_events.push(name.to_string());
+        StructType::GenericEvent
+    } else {
+        StructType::Event { no_sequence_number }
+    };
+    let mut event_struct_parser = StructContentParser::new(&event_name, struct_type,
+                                                           &parse.types.renames);
+
     loop {
         match recv(events) {
             XmlEvent::EndElement{ref name} if name.local_name == "event" => break,
 
             // `<doc>`
+            XmlEvent::StartElement{ref name, ..} if name.local_name == "doc" => {
+                parse_doc(&mut docs, events);
+            },
+
+            ev => event_struct_parser.feed(ev, events),
+        }
+    }
+
+    parse.typedefs.write_all(&docs).unwrap();
+    writeln!(parse.typedefs).unwrap();
+
+    parse.altenums.extend(event_struct_parser.alternate_enums().iter().cloned());
+    let mut switches = Vec::new();
+    event_struct_parser.finish(&mut parse.typedefs, &mut switches, &mut parse.types);
+    parse.typedefs.write_all(&switches).unwrap();
+
+    write_event_variant(parse, name, number, generic);
+}
+
+/// Adds the variant of an event to `Event`, and its decoding to `Event::decode` or, for the
+/// generic events, to `Event::decode_generic`.
+///
+/// The other events of extensions are left out, as their numbers are relative to the first
+/// event of the extension. They are received as `Event::Unknown`.
+fn write_event_variant(parse: &mut ParseResult, name: &str, number: u8, generic: bool) {
+    match (parse.extension.as_ref(), generic) {
+        (None, _) => {
+            writeln!(parse.events_decoders, "            {num} => \
+                                             Event::{name}({name}Event::socket_recv(&mut cursor, order)?),",
+                     num = number, name = name).unwrap();
+        },
+        (Some((xname, _)), true) => {
+            writeln!(parse.generic_events_decoders, "            (Some(\"{xname}\"), {num}) => \
+                                                     Event::{name}({name}Event::socket_recv(&mut cursor, order)?),",
+                     xname = xname, num = number, name = name).unwrap();
+        },
+        (Some(_), false) => return,
+    }
+
+    writeln!(parse.events_list, "    {}({}Event),", name, name).unwrap();
+}
+
+fn parse_error<R>(parse: &mut ParseResult, events: &mut EventReader<R>, name: &str, number: u8)
+                  where R: Read
+{
+    let error_name = format!("{}Error", name);
+    let mut error_struct_parser = StructContentParser::new(&error_name, StructType::Error,
+                                                           &parse.types.renames);
+
+    loop {
+        match recv(events) {
+            XmlEvent::EndElement{ref name} if name.local_name == "error" => break,
+            ev => error_struct_parser.feed(ev, events),
+        }
+    }
+
+    parse.altenums.extend(error_struct_parser.alternate_enums().iter().cloned());
+    let fields = error_struct_parser.finish(&mut parse.typedefs, &mut Vec::new(),
+                                            &mut parse.types);
+
+    let has_bad_value = fields.iter().any(|(name, ty)| name == "bad_value" &&
+                                                                  ty == "CARD32");
+    if has_bad_value {
+        parse.types.errors_with_bad_value.push(name.to_string());
+    }
+
+    write_error_variant(parse, name, number, has_bad_value);
+}
+
+/// Adds the variant of an error to `XError`, its decoding to `XError::decode` and its common
+/// fields to `XError::header`.
+///
+/// The errors of extensions are decoded from their number relative to the first error of the
+/// extension, which is obtained from the server.
+fn write_error_variant(parse: &mut ParseResult, name: &str, number: u8, has_bad_value: bool) {
+    writeln!(parse.errors_list, "    {}({}Error),", name, name).unwrap();
+    let extension = match parse.extension {
+        Some((ref xname, _)) => format!("Some(\"{}\")", xname),
+        None => "None".to_string(),
+    };
+    writeln!(parse.errors_decoders, "            ({extension}, {num}) => \
+                                     XError::{name}({name}Error::socket_recv(&mut cursor, order)?),",
+             extension = extension, num = number, name = name).unwrap();
+    writeln!(parse.errors_headers, "            XError::{name}(ref e) => (\"{name}\", e.sequence, \
+                                    e.major_opcode, e.minor_opcode, {bad_value}, e.request),",
+             name = name,
+             bad_value = if has_bad_value { "Some(e.bad_value)" } else { "None" }).unwrap();
+    writeln!(parse.errors_requests, "            XError::{name}(ref mut e) => &mut e.request,",
+             name = name).unwrap();
+}
+
+fn parse_union<R>(parse: &mut ParseResult, events: &mut EventReader<R>, union_name: &str)
+                  where R: Read
+{
+    let mut union_parser = StructContentParser::new(union_name, StructType::Union,
+                                                    &parse.types.renames);
+
+    loop {
+        match recv(events) {
+            XmlEvent::EndElement{ref name} if name.local_name == "union" => break,
+            ev => union_parser.feed(ev, events),
+        }
+    }
+
+    union_parser.finish(&mut parse.typedefs, &mut Vec::new(), &mut parse.types);
+}
+
+fn parse_enum<R>(parse: &mut ParseResult, events: &mut EventReader<R>, enum_name: &str)
+                 where R: Read
+{
+    let mut docs = Vec::new();
+
+    // list of (item name, value, is_bit)
+    let mut items: Vec<(String, u32, bool)> = Vec::new();
+    let mut current_item: Option<String> = None;
+    let mut current_kind: Option<bool> = None;
+
+    loop {
+        match recv(events) {
+            XmlEvent::EndElement{ref name} if name.local_name == "enum" => break,
+
+            // `<doc>`
+            XmlEvent::StartElement{ref name, ..} if name.local_name == "doc" => {
+                parse_doc(&mut docs, events);
+            },
+
+            // `<item name="...">`
             XmlEvent::StartElement{ref name, ref attributes, ..}
-                if name.local_name == "doc" =>
+                if name.local_name == "item" =>
             {
-                parse_doc(&mut docs, events);
+                current_item = Some(get_attribute(attributes, "name").unwrap());
+                current_kind = None;
+            },
+            XmlEvent::EndElement{ref name} if name.local_name == "item" => {
+                let item = current_item.take().unwrap();
+
+                // items without any value simply follow the previous one
+                if current_kind.is_none() {
+                    let value = items.last().map(|&(_, v, _)| v + 1).unwrap_or(0);
+                    items.push((item, value, false));
+                }
+            },
+
+            // `<value>` and `<bit>`
+            XmlEvent::StartElement{ref name, ..}
+                if name.local_name == "value" || name.local_name == "bit" =>
+            {
+                current_kind = Some(name.local_name == "bit");
+            },
+            XmlEvent::EndElement{ref name, ..}
+                if name.local_name == "value" || name.local_name == "bit" => (),
+            XmlEvent::Characters(ref value) => {
+                let is_bit = current_kind.unwrap();
+                let item = current_item.clone().unwrap();
+                let value = parse_number(value.trim()).unwrap_or_else(|| {
+                    panic!("Invalid value `{}` for the enum item {}::{}", value.trim(), enum_name,
+                           item)
+                });
+                items.push((item, if is_bit { 1 << value } else { value }, is_bit));
             },
 
-            msg => ()       // FIXME: err
+            msg => panic!("Unexpected {:?} in `<enum>`", msg),
         }
     }
+
+    parse.types.enums.insert(enum_name.to_string(),
+                             items.iter().map(|&(ref i, v, _)| (i.clone(), v)).collect());
+
+    parse.typedefs.write_all(&docs).unwrap();
+    writeln!(parse.typedefs).unwrap();
+
+    if items.iter().any(|&(_, _, is_bit)| is_bit) {
+        write_bitflags(&mut parse.typedefs, enum_name, &items);
+    } else {
+        write_value_enum(&mut parse.typedefs, enum_name, &items);
+    }
 }
 
-fn rustyfi_name(name: String) -> String {
-    if name == "type" {
-        "ty".to_string()
+/// Writes a Rust enum corresponding to an `<enum>` whose items are `<value>`s.
+fn write_value_enum<W>(output: &mut W, enum_name: &str, items: &[(String, u32, bool)])
+                       where W: Write
+{
+    // some items, like the names of the predefined atoms, keep the case of the XML definitions
+    let camel_case = items.iter().all(|(item, _, _)| {
+        let item = item.trim_start_matches('_');
+        !item.contains('_') && !item.starts_with(char::is_lowercase)
+    });
+
+    writeln!(output, "#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]").unwrap();
+    if !camel_case {
+        writeln!(output, "#[allow(non_camel_case_types)]").unwrap();
+    }
+    writeln!(output, "pub enum {} {{", enum_name).unwrap();
+    for (item, _, _) in items {
+        writeln!(output, "\t{},", rustyfi_variant(item)).unwrap();
+    }
+    // servers can send values that we don't know, for example from newer versions of extensions
+    writeln!(output, "\t/// A value that isn't part of the protocol definition.").unwrap();
+    writeln!(output, "\tUnknownValue(u32),").unwrap();
+    writeln!(output, "}}").unwrap();
+
+    writeln!(output, "impl {} {{", enum_name).unwrap();
+    writeln!(output, "\t/// Returns the value of this variant in the protocol.").unwrap();
+    writeln!(output, "\tpub fn to_raw(&self) -> u32 {{ match *self {{").unwrap();
+    for &(ref item, value, _) in items {
+        writeln!(output, "\t\t{}::{} => {},", enum_name, rustyfi_variant(item), value).unwrap();
+    }
+    writeln!(output, "\t\t{}::UnknownValue(raw) => raw,", enum_name).unwrap();
+    writeln!(output, "\t}} }}").unwrap();
+
+    // some enums have several items with the same value, the first one wins
+    writeln!(output, "\t/// Builds the variant from its value in the protocol.").unwrap();
+    writeln!(output, "\tpub fn from_raw(raw: u32) -> {} {{ match raw {{", enum_name).unwrap();
+    let mut seen = Vec::new();
+    for &(ref item, value, _) in items {
+        if seen.contains(&value) { continue; }
+        seen.push(value);
+        writeln!(output, "\t\t{} => {}::{},", value, enum_name, rustyfi_variant(item)).unwrap();
+    }
+    writeln!(output, "\t\traw => {}::UnknownValue(raw),", enum_name).unwrap();
+    writeln!(output, "\t}} }}").unwrap();
+    writeln!(output, "}}").unwrap();
+}
+
+/// Writes a bitflags-like type corresponding to an `<enum>` whose items are `<bit>`s.
+fn write_bitflags<W>(output: &mut W, enum_name: &str, items: &[(String, u32, bool)])
+                     where W: Write
+{
+    let all = items.iter().fold(0, |all, &(_, value, _)| all | value);
+
+    writeln!(output, r#"#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Default)]
+pub struct {name} {{
+    bits: u32,
+}}
+
+impl {name} {{"#, name = enum_name).unwrap();
+
+    for &(ref item, value, _) in items {
+        writeln!(output, "\tpub const {}: {} = {} {{ bits: {} }};",
+                 rustyfi_constant(item), enum_name, enum_name, value).unwrap();
+    }
+
+    writeln!(output, r#"
+    /// Returns a value where no flag is set.
+    pub fn empty() -> {name} {{
+        {name} {{ bits: 0 }}
+    }}
+
+    /// Returns a value where all the known flags are set.
+    pub fn all() -> {name} {{
+        {name} {{ bits: {all} }}
+    }}
+
+    /// Returns the raw value of the flags.
+    pub fn bits(&self) -> u32 {{
+        self.bits
+    }}
+
+    /// Builds the flags from a raw value. Unknown bits are kept.
+    pub fn from_bits(bits: u32) -> {name} {{
+        {name} {{ bits }}
+    }}
+
+    /// Returns the value of the flags in the protocol.
+    pub fn to_raw(&self) -> u32 {{
+        self.bits
+    }}
+
+    /// Builds the flags from their value in the protocol.
+    pub fn from_raw(raw: u32) -> {name} {{
+        {name} {{ bits: raw }}
+    }}
+
+    /// Returns true if no flag is set.
+    pub fn is_empty(&self) -> bool {{
+        self.bits == 0
+    }}
+
+    /// Returns true if all the flags of `other` are set in `self`.
+    pub fn contains(&self, other: {name}) -> bool {{
+        (self.bits & other.bits) == other.bits
+    }}
+
+    /// Sets the flags of `other`.
+    pub fn insert(&mut self, other: {name}) {{
+        self.bits |= other.bits;
+    }}
+
+    /// Unsets the flags of `other`.
+    pub fn remove(&mut self, other: {name}) {{
+        self.bits &= !other.bits;
+    }}
+}}
+
+impl BitOr for {name} {{
+    type Output = {name};
+    fn bitor(self, other: {name}) -> {name} {{
+        {name} {{ bits: self.bits | other.bits }}
+    }}
+}}
+
+impl BitAnd for {name} {{
+    type Output = {name};
+    fn bitand(self, other: {name}) -> {name} {{
+        {name} {{ bits: self.bits & other.bits }}
+    }}
+}}
+
+impl BitXor for {name} {{
+    type Output = {name};
+    fn bitxor(self, other: {name}) -> {name} {{
+        {name} {{ bits: self.bits ^ other.bits }}
+    }}
+}}
+
+impl Not for {name} {{
+    type Output = {name};
+    fn not(self) -> {name} {{
+        {name} {{ bits: !self.bits & {all} }}
+    }}
+}}"#, name = enum_name, all = all).unwrap();
+}
+
+/// Writes conversions from the enums that are used as `altenum` to the corresponding XID types.
+fn write_altenum_conversions(parse: &mut ParseResult) {
+    let mut done = Vec::new();
+
+    for (ty, enum_name) in parse.altenums.iter() {
+        if !parse.types.xidtypes.contains(ty) { continue; }
+        if done.contains(&(ty, enum_name)) { continue; }
+        done.push((ty, enum_name));
+
+        writeln!(parse.typedefs, r#"
+impl From<{en}> for {ty} {{
+    fn from(value: {en}) -> {ty} {{
+        {ty}(value.to_raw())
+    }}
+}}"#, en = enum_name, ty = ty).unwrap();
+    }
+}
+
+fn rustyfi_variant(name: &str) -> String {
+    // identifiers can't start with a digit
+    if name.chars().next().map(|c| c.is_ascii_digit()).unwrap_or(false) {
+        format!("_{}", name)
     } else {
-        name
+        name.to_string()
+    }
+}
+
+fn rustyfi_constant(name: &str) -> String {
+    rustyfi_variant(&snake_case(name).to_uppercase())
+}
+
+/// Turns a name like `sizeID` into `size_id`.
+fn snake_case(name: &str) -> String {
+    let chars: Vec<char> = name.chars().collect();
+    let mut result = String::new();
+
+    for (i, &c) in chars.iter().enumerate() {
+        if i >= 1 && c.is_uppercase() {
+            let prev = chars[i - 1];
+            let next_is_lower = chars.get(i + 1).map(|c| c.is_lowercase()).unwrap_or(false);
+            if prev.is_lowercase() || prev.is_ascii_digit() || (prev.is_uppercase() && next_is_lower) {
+                result.push('_');
+            }
+        }
+
+        result.extend(c.to_lowercase());
+    }
+
+    result
+}
+
+/// Turns a name like `value_list` into `ValueList`.
+fn camel_case(name: &str) -> String {
+    name.split('_').map(|word| {
+        let mut chars = word.chars();
+        match chars.next() {
+            Some(first) => first.to_uppercase().chain(chars).collect::<String>(),
+            None => String::new(),
+        }
+    }).collect::<Vec<_>>().concat()
+}
+
+/// Returns the Rust type corresponding to a type of the XML definitions.
+fn rust_type(ty: &str) -> String {
+    // the types of other headers can be prefixed with the name of the header, like in
+    // `xproto:WINDOW`, but all the types end up in the same module
+    let ty = ty.rsplit(':').next().unwrap();
+
+    match ty {
+        "char" | "void" => "u8".to_string(),
+        "float" => "f32".to_string(),
+        "double" => "f64".to_string(),
+        ty => ty.to_string()
+    }
+}
+
+fn rustyfi_name(name: String) -> String {
+    match &name[..] {
+        "type" => "ty".to_string(),
+        // `cursor` is the parameter of the generated `socket_recv` functions
+        "cursor" => "cursor_".to_string(),
+        // the names of the fields are in camel case in a few extensions
+        _ => snake_case(&name)
     }
 }
@@ -1,142 +1,1417 @@
 use xml::reader::EventReader;
-use xml::reader::events::XmlEvent;
+use xml::reader::XmlEvent;
 
-use std::io::{Write, Read};
+use std::collections::HashMap;
+use std::io::{Cursor, Write, Read};
 
-use {rustyfi_name, get_attribute};
+use expression::{without_parens, Expression, ExpressionContext};
+use value_lists;
+use {rustyfi_name, rustyfi_constant, camel_case, get_attribute, recv, skip_element, rust_type};
+use TypesInfo;
 
 pub struct StructContentParser {
-    /// List of fields with (name, type)
-    fields: Vec<(String, String)>,
-    definition: Vec<u8>,
-    send_impl: Option<(Vec<u8>, bool)>,
-    socket_send_impl: Option<Vec<u8>>,
+    name: String,
+    ty: StructType,
+    /// Content of the struct, in the order of the XML definitions
+    members: Vec<Member>,
+    /// List of (field type, enum name) for the fields with an `altenum` attribute
+    altenums: Vec<(String, String)>,
+    /// Types of the header being parsed that have been renamed, see `TypesInfo::renames`
+    renames: HashMap<String, String>,
 }
 
 pub enum StructType {
     Struct,
-    Request { opcode: u8 },
+    /// `<request>`. The opcode of an extension request is its minor opcode, as the major opcode
+    /// of the extension is only known at runtime.
+    Request { name: String, opcode: u8, extension: bool },
+    /// The content of a `<reply>`, which is preceded by a header
+    Reply,
+    /// The content of an `<event>`, which is preceded by the response type
+    Event { no_sequence_number: bool },
+    /// The content of an `<event xge="true">`, which is preceded by the response type, the
+    /// major opcode of the extension, the sequence number, the length and the event type
+    GenericEvent,
+    /// `<union>`, whose members share the same bytes
+    Union,
+    /// The content of an `<error>`, which is preceded by the error code and the sequence number
+    Error,
 }
 
-impl StructContentParser {
-    pub fn new(name: &str, ty: StructType) -> StructContentParser {
-        let mut definition = Vec::new();
-        writeln!(definition, "pub struct {} {{", name).unwrap();
+/// Element of the content of a struct.
+enum Member {
+    /// `<pad bytes="N" />`
+    Pad(usize),
+    /// `<pad align="N" />`
+    Align(usize),
+    /// `<field type="..." name="..." />`
+    Field {
+        name: String,
+        /// Type on the wire
+        ty: String,
+        /// Generated type from the `enum` or `mask` attribute
+        enum_ty: Option<String>,
+        altenum: Option<String>,
+    },
+    /// `<list type="..." name="...">length</list>`
+    List {
+        name: String,
+        /// Type of the elements
+        ty: String,
+        /// If `None`, the list goes until the end of the data
+        length: Option<Expression>,
+    },
+    /// `<exprfield type="..." name="...">expression</exprfield>`
+    ExprField { name: String, ty: String, expression: Expression },
+    /// `<switch name="...">expression cases</switch>`
+    Switch(Switch),
+}
 
-        let send_impl;
-        let socket_send_impl;
+/// Content of a `<switch>`. Each case is stored as an `Option` in a generated struct.
+struct Switch {
+    name: String,
+    /// Name of the generated struct
+    ty: String,
+    expression: Expression,
+    cases: Vec<Case>,
+}
 
-        match ty {
-            StructType::Request { opcode } => {
-                let mut send = Vec::new();
-                writeln!(send, "impl {} {{", name).unwrap();
-                writeln!(send, "    fn send(&self, socket: &mut TcpStream, sequence: u32) \
-                                            -> IoResult<()> {{").unwrap();
+/// A `<bitcase>` or a `<case>` of a switch.
+struct Case {
+    /// True for `<bitcase>`, false for `<case>`
+    bitcase: bool,
+    /// Name of the field in the struct of the switch
+    name: String,
+    /// If the case contains more than one member, name of the struct that holds them
+    ty: Option<String>,
+    /// The case is present if the expression of the switch matches one of these
+    values: Vec<Expression>,
+    members: Vec<Member>,
+}
 
-                writeln!(send, "try!(socket.write_u8({}));", opcode).unwrap();
+/// If a field is computed from another member, indicates which one.
+enum Derived<'a> {
+    /// The field is the length of a list.
+    ListLength(&'a str),
+    /// The field is the mask of a switch that only contains `<bitcase>`s.
+    SwitchMask(&'a Switch),
+}
 
-                send_impl = Some((send, false));
-                socket_send_impl = None;
-            },
+/// Where the generated code takes the values of the fields from.
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Context {
+    /// The values are in a struct, which the scope gives access to.
+    Send,
+    /// The values have been read in local variables.
+    Recv,
+}
 
-            StructType::Struct => {
-                let mut socket_send = Vec::new();
-                writeln!(socket_send, "impl SocketSend for {} {{", name).unwrap();
-                writeln!(socket_send, "    fn socket_send(&self, socket: &mut TcpStream) \
-                                                          -> IoResult<()> {{").unwrap();
-                
-                send_impl = None;
-                socket_send_impl = Some(socket_send);
-            },
+/// List of members that the generated code has access to.
+struct Scope<'a> {
+    members: &'a [Member],
+    /// Code that gives access to the struct containing the members when sending, like `self.`.
+    /// If `single` is true, the scope contains one member and this is the code to access it.
+    access: String,
+    single: bool,
+    /// True if the lists are borrowed by the struct, like in requests.
+    borrowed: bool,
+    /// Scope of the struct that contains the switch, for the members of a case
+    parent: Option<&'a Scope<'a>>,
+}
+
+struct ScopeContext<'a> {
+    scope: &'a Scope<'a>,
+    types: &'a TypesInfo,
+    context: Context,
+}
+
+impl<'a> ExpressionContext for ScopeContext<'a> {
+    fn field(&self, name: &str) -> String {
+        self.scope.field_value(name, self.context)
+    }
+
+    fn param(&self, name: &str) -> String {
+        name.to_string()
+    }
+
+    fn list(&self, name: &str) -> String {
+        match self.context {
+            Context::Send => self.scope.access(name),
+            Context::Recv => name.to_string(),
         }
+    }
 
+    fn enum_item(&self, enum_name: &str, item: &str) -> u32 {
+        self.types.enum_item(enum_name, item)
+    }
+}
+
+impl StructContentParser {
+    pub fn new(name: &str, ty: StructType, renames: &HashMap<String, String>)
+               -> StructContentParser
+    {
         StructContentParser {
-            fields: Vec::with_capacity(0),
-            definition: definition,
-            send_impl: send_impl,
-            socket_send_impl: socket_send_impl,
+            name: name.to_string(),
+            ty,
+            members: Vec::new(),
+            altenums: Vec::new(),
+            renames: renames.clone(),
+        }
+    }
+
+    /// Returns the Rust type corresponding to a type of the XML definitions.
+    fn rust_type(&self, ty: &str) -> String {
+        match self.renames.get(ty) {
+            Some(ty) => ty.clone(),
+            None => rust_type(ty)
         }
     }
 
     pub fn feed<R>(&mut self, event: XmlEvent, events_list: &mut EventReader<R>) where R: Read {
         match event {
-            // `<pad bytes="N" />`
-            XmlEvent::EndElement{ref name} if name.local_name == "pad" => (),
+            // `<pad bytes="N" />` or `<pad align="N" />`
             XmlEvent::StartElement{ref name, ref attributes, ..}
                 if name.local_name == "pad" =>
             {
-                let bytes = get_attribute(attributes, "bytes").unwrap();
-                let bytes: usize = bytes.parse().unwrap();
-
-                if let Some((ref mut send_impl, _)) = self.send_impl {
-                    writeln!(send_impl, "\tfor _ in 0 .. {} {{ \
-                                               try!(socket.write_u8(0)); \
-                                           }}", bytes).unwrap();
+                if let Some(bytes) = get_attribute(attributes, "bytes") {
+                    self.members.push(Member::Pad(bytes.parse().unwrap()));
+                } else {
+                    let align = get_attribute(attributes, "align").unwrap();
+                    self.members.push(Member::Align(align.parse().unwrap()));
                 }
 
-                if let Some(ref mut socket_send_impl) = self.socket_send_impl {
-                    writeln!(socket_send_impl, "\tfor _ in 0 .. {} {{ \
-                                                      try!(socket.write_u8(0)); \
-                                                  }}", bytes).unwrap();
-                }
+                skip_element(events_list);
             },
 
             // `<field type="..." name="..." />`
-            XmlEvent::EndElement{ref name} if name.local_name == "field" => (),
             XmlEvent::StartElement{ref name, ref attributes, ..}
                 if name.local_name == "field" =>
             {
-                let ty = get_attribute(attributes, "type").unwrap();
+                let ty = self.rust_type(&get_attribute(attributes, "type").unwrap());
                 let name = rustyfi_name(get_attribute(attributes, "name").unwrap());
-                writeln!(self.definition, "\tpub {}: {},", name, ty).unwrap();
 
-                if let Some((ref mut send_impl, _)) = self.send_impl {
-                    writeln!(send_impl, "\ttry!(self.{}.socket_send(&mut socket));", name).unwrap();
-                }
+                // fields with an `enum` or a `mask` use the corresponding generated type, and are
+                // converted to their wire type when sent
+                let enum_ty = get_attribute(attributes, "enum")
+                                    .or_else(|| get_attribute(attributes, "mask"))
+                                    .and_then(|e| if ty != "BOOL" { Some(e) } else { None })
+                                    .map(|e| self.rust_type(&e));
 
-                if let Some(ref mut socket_send_impl) = self.socket_send_impl {
-                    writeln!(socket_send_impl, "\t\ttry!(self.{}.socket_send(&mut socket));", name).unwrap();
+                let altenum = get_attribute(attributes, "altenum").map(|e| self.rust_type(&e));
+                if let Some(ref altenum) = altenum {
+                    self.altenums.push((ty.clone(), altenum.clone()));
                 }
 
-                self.fields.push((name, ty));
+                self.members.push(Member::Field {
+                    name,
+                    ty,
+                    enum_ty,
+                    altenum,
+                });
+
+                skip_element(events_list);
+            },
+
+            // `<list type="..." name="...">`
+            XmlEvent::StartElement{ref name, ref attributes, ..}
+                if name.local_name == "list" =>
+            {
+                let ty = self.rust_type(&get_attribute(attributes, "type").unwrap());
+                let name = rustyfi_name(get_attribute(attributes, "name").unwrap());
+                let length = parse_optional_expression(events_list);
+
+                self.members.push(Member::List {
+                    name,
+                    ty,
+                    length,
+                });
             },
 
+            // `<exprfield type="..." name="...">`
+            XmlEvent::StartElement{ref name, ref attributes, ..}
+                if name.local_name == "exprfield" =>
+            {
+                let ty = self.rust_type(&get_attribute(attributes, "type").unwrap());
+                let name = rustyfi_name(get_attribute(attributes, "name").unwrap());
+                let expression = parse_optional_expression(events_list).unwrap();
+
+                self.members.push(Member::ExprField {
+                    name,
+                    ty,
+                    expression,
+                });
+            },
+
+            // `<switch name="...">`
+            XmlEvent::StartElement{ref name, ref attributes, ..}
+                if name.local_name == "switch" =>
+            {
+                let name = rustyfi_name(get_attribute(attributes, "name").unwrap());
+                let switch = self.parse_switch(name, events_list);
+                self.members.push(Member::Switch(switch));
+            },
+
+            // `<valueparam value-mask-type="..." value-mask-name="..." value-list-name="..." />`
+            XmlEvent::StartElement{ref name, ref attributes, ..}
+                if name.local_name == "valueparam" =>
+            {
+                let mask_ty = get_attribute(attributes, "value-mask-type").unwrap();
+                let mask_name = get_attribute(attributes, "value-mask-name").unwrap();
+                let list_name = get_attribute(attributes, "value-list-name").unwrap();
+                skip_element(events_list);
+
+                self.parse_valueparam(&mask_ty, &mask_name, &list_name);
+            },
+
+            // ignoring `<doc>` and the elements that aren't supported
+            XmlEvent::StartElement{..} => skip_element(events_list),
+
             _ => ()
-            //ev => panic!("Unexpected element in XML definitions: {:?}", ev)
         }
+    }
+
+    /// Returns the list of (field type, enum name) for the fields with an `altenum` attribute.
+    pub fn alternate_enums(&self) -> &[(String, String)] {
+        &self.altenums
+    }
+
+    /// Writes the definition of the struct and its implementations to `dest`, and the
+    /// definitions of the structs of its switches to `switches_dest`.
+    ///
+    /// Returns the list of (name, type) of the values that the user must provide.
+    pub fn finish<W, S>(mut self, dest: &mut W, switches_dest: &mut S, types: &mut TypesInfo)
+                        -> Vec<(String, String)> where W: Write, S: Write
+    {
+        if let StructType::Union = self.ty {
+            self.write_union(dest, types);
+            return Vec::new();
+        }
+
+        self.add_implicit_alignment(types);
+        sort_bitcases(&mut self.members, types);
 
-        if let Some((ref mut send_impl, ref mut seqnum_sent)) = self.send_impl {
-            if !*seqnum_sent {
-                writeln!(send_impl, "try!(sequence.socket_send(socket));").unwrap();
-                *seqnum_sent = true;
+        for member in self.members.iter() {
+            if let Member::Switch(ref switch) = *member {
+                write_switch_types(switch, switches_dest, types);
             }
         }
+
+        let is_request = matches!(self.ty, StructType::Request { .. });
+        let scope = Scope { members: &self.members, access: "self.".to_string(), single: false,
+                            borrowed: is_request, parent: None };
+        let user_fields = scope.user_fields(is_request);
+
+        let lifetime = if user_fields.iter().any(|(_, ty)| ty.starts_with('&')) {
+            "<'a>"
+        } else {
+            ""
+        };
+
+        // definition
+        if !is_request {
+            writeln!(dest, "#[derive(Debug, Clone)]").unwrap();
+        }
+        writeln!(dest, "pub struct {}{} {{", self.name, lifetime).unwrap();
+        let header_fields = self.header_fields(&user_fields);
+        for &(name, ty, doc) in header_fields.iter() {
+            writeln!(dest, "\t/// {}", doc).unwrap();
+            writeln!(dest, "\tpub {}: {},", name, ty).unwrap();
+        }
+        for (name, ty) in user_fields.iter() {
+            if let Some(altenum) = scope.altenum(name) {
+                writeln!(dest, "\t/// Can also contain a value of `{}`.", altenum).unwrap();
+            }
+
+            let ty = match ty.strip_prefix('&') {
+                Some(borrowed) => format!("&'a {}", borrowed),
+                None => ty.clone()
+            };
+            writeln!(dest, "\tpub {}: {},", name, ty).unwrap();
+        }
+        writeln!(dest, "}}").unwrap();
+
+        match self.ty {
+            StructType::Request { opcode, extension, .. } => {
+                self.write_request_send(&scope, dest, types, opcode, extension, lifetime);
+            },
+
+            StructType::Struct => {
+                self.write_socket_send(&scope, dest, types);
+                self.write_socket_recv(&scope, dest, types);
+            },
+
+            StructType::Reply | StructType::Event { .. } | StructType::GenericEvent |
+            StructType::Error => {
+                self.write_socket_recv(&scope, dest, types)
+            },
+
+            StructType::Union => unreachable!(),
+        }
+
+        user_fields
     }
 
-    pub fn finish<W>(mut self, dest: &mut W) -> Vec<(String, String)> where W: Write {
-        dest.write_all(&self.definition).unwrap();
-        if self.fields.is_empty() { writeln!(dest, "e: ()").unwrap(); }
+    /// Parses a `<switch>` whose start element has just been read.
+    fn parse_switch<R>(&mut self, name: String, events: &mut EventReader<R>) -> Switch
+                       where R: Read
+    {
+        let ty = match self.ty {
+            StructType::Request { ref name, .. } => format!("{}Aux", name),
+            _ => format!("{}{}", self.name, camel_case(&name)),
+        };
+
+        let mut expression = None;
+        let mut cases = Vec::new();
+
+        loop {
+            match recv(events) {
+                XmlEvent::EndElement{ref name} if name.local_name == "switch" => break,
+
+                XmlEvent::StartElement{ref name, ref attributes, ..}
+                    if Expression::is_expression(&name.local_name) =>
+                {
+                    expression = Some(Expression::parse(&name.local_name, attributes, events));
+                },
+
+                // `<bitcase>` or `<case>`
+                XmlEvent::StartElement{ref name, ref attributes, ..}
+                    if name.local_name == "bitcase" || name.local_name == "case" =>
+                {
+                    let bitcase = name.local_name == "bitcase";
+                    let case_name = get_attribute(attributes, "name").map(rustyfi_name);
+                    let index = cases.len();
+                    cases.push(self.parse_case(&ty, bitcase, case_name, index, events));
+                },
+
+                XmlEvent::StartElement{..} => skip_element(events),
+                _ => ()
+            }
+        }
+
+        Switch {
+            name,
+            ty,
+            expression: expression.expect("`<switch>` without an expression"),
+            cases,
+        }
+    }
+
+    /// Parses a `<bitcase>` or a `<case>` whose start element has just been read.
+    fn parse_case<R>(&mut self, switch_ty: &str, bitcase: bool, name: Option<String>,
+                     index: usize, events: &mut EventReader<R>) -> Case where R: Read
+    {
+        let end = if bitcase { "bitcase" } else { "case" };
+        let mut values = Vec::new();
+
+        // the members of the case are parsed as if they were in a struct
+        let struct_name = format!("{}{}", switch_ty,
+                                  camel_case(name.as_ref().map(|n| &n[..]).unwrap_or("case")));
+        let mut parser = StructContentParser::new(&struct_name, StructType::Struct, &self.renames);
+
+        loop {
+            match recv(events) {
+                XmlEvent::EndElement{ref name} if name.local_name == end => break,
+
+                XmlEvent::StartElement{ref name, ref attributes, ..}
+                    if Expression::is_expression(&name.local_name) =>
+                {
+                    values.push(Expression::parse(&name.local_name, attributes, events));
+                },
+
+                ev => parser.feed(ev, events),
+            }
+        }
+
+        self.altenums.extend(parser.altenums);
+        let members = parser.members;
+
+        // a case with a single member is directly stored in the struct of the switch
+        let single = if members.len() == 1 {
+            match members[0] {
+                Member::Field { ref name, .. } | Member::List { ref name, .. } => Some(name.clone()),
+                Member::Switch(ref switch) => Some(switch.name.clone()),
+                _ => None
+            }
+        } else {
+            None
+        };
+
+        let name = match (name, single.clone()) {
+            (Some(name), _) => name,
+            (None, Some(single)) => single,
+            (None, None) => match values.first() {
+                Some(Expression::EnumRef(_, item)) => rustyfi_constant(item).to_lowercase(),
+                _ => format!("case{}", index),
+            },
+        };
+
+        let ty = match single {
+            Some(_) => None,
+            None => Some(format!("{}{}", switch_ty, camel_case(&name))),
+        };
+
+        Case {
+            bitcase,
+            name,
+            ty,
+            values,
+            members,
+        }
+    }
+
+    /// Turns a `<valueparam>` into a field for the mask and a switch for the values.
+    fn parse_valueparam(&mut self, mask_ty: &str, mask_name: &str, list_name: &str) {
+        let has_mask = self.members.iter().any(|m| match *m {
+            Member::Field { ref name, .. } => name == mask_name,
+            _ => false
+        });
+
+        if !has_mask {
+            self.members.push(Member::Field {
+                name: mask_name.to_string(),
+                ty: mask_ty.to_string(),
+                enum_ty: None,
+                altenum: None,
+            });
+
+            if mask_ty == "CARD16" {
+                self.members.push(Member::Pad(2));
+            }
+        }
+
+        // the definitions don't give the types of the values, so we use a `<switch>` if we know
+        // them, and a list of CARD32 otherwise
+        let switch = match self.ty {
+            StructType::Request { ref name, .. } => value_lists::switch(name),
+            _ => None,
+        };
+
+        let content = match switch {
+            Some(switch) => switch.to_string(),
+            None => format!("<list type=\"CARD32\" name=\"{}\"><popcount><fieldref>{}</fieldref>\
+                             </popcount></list>", list_name, mask_name),
+        };
+
+        let content = format!("<valueparam>{}</valueparam>", content);
+        let mut events = EventReader::new(Cursor::new(content.as_bytes()));
+        match recv(&mut events) {
+            XmlEvent::StartElement{ref name, ..} if name.local_name == "valueparam" => (),
+            msg => panic!("Unexpected {:?}", msg),
+        }
+
+        loop {
+            match recv(&mut events) {
+                XmlEvent::EndElement{ref name} if name.local_name == "valueparam" => break,
+                ev => self.feed(ev, &mut events),
+            }
+        }
+    }
+
+    /// Like XCB, aligns the members that follow a list to the size of their type (up to 4 bytes).
+    fn add_implicit_alignment(&mut self, types: &mut TypesInfo) {
+        let mut variable_size = false;
+        let mut members = Vec::with_capacity(self.members.len());
+
+        for member in self.members.drain(..) {
+            let align = match member {
+                Member::Field { ref ty, .. } | Member::List { ref ty, .. } if variable_size => {
+                    types.sizes.get(ty).map(|&s| if s > 4 { 4 } else { s }).unwrap_or(4)
+                },
+                _ => 1
+            };
+
+            match member {
+                Member::List { .. } | Member::Switch(_) => variable_size = true,
+                Member::Align(_) => variable_size = false,
+                _ => ()
+            };
+
+            if align > 1 {
+                members.push(Member::Align(align));
+            }
+
+            members.push(member);
+        }
+
+        // registering the size of the struct if it is fixed
+        if let StructType::Struct = self.ty {
+            let size = members.iter().try_fold(0, |size, member| {
+                match *member {
+                    Member::Pad(bytes) => Some(size + bytes),
+                    Member::Field { ref ty, .. } => types.sizes.get(ty).map(|t| size + t),
+                    _ => None
+                }
+            });
+
+            if let Some(size) = size {
+                types.sizes.insert(self.name.clone(), size);
+            }
+        }
+
+        self.members = members;
+    }
+
+    /// Returns the list of (name, type, documentation) of the fields that are decoded from the
+    /// header of the struct, in addition to the fields of the XML definitions.
+    fn header_fields(&self, user_fields: &[(String, String)])
+                     -> Vec<(&'static str, &'static str, &'static str)>
+    {
+        match self.ty {
+            StructType::Event { .. } | StructType::GenericEvent => {
+                vec![("send_event", "bool",
+                      "True if the event was sent by a client with `SendEvent`.")]
+            },
+
+            StructType::Error => {
+                let mut fields = vec![("sequence", "u16",
+                                       "Sequence number of the request that caused the error.")];
+
+                // most errors already define these fields
+                if !user_fields.iter().any(|(name, _)| name == "major_opcode") {
+                    fields.push(("minor_opcode", "CARD16", "Minor opcode of the request."));
+                    fields.push(("major_opcode", "CARD8", "Major opcode of the request."));
+                }
+
+                fields.push(("request", "Option<&'static str>",
+                             "Name of the request, unless its extension hasn't been queried."));
+
+                fields
+            },
+
+            _ => Vec::new()
+        }
+    }
+
+    /// Writes a `<union>` as a struct that holds the raw bytes, with a method that decodes each
+    /// member.
+    fn write_union<W>(&self, dest: &mut W, types: &mut TypesInfo) where W: Write {
+        let size = self.members.iter().map(|member| {
+            match *member {
+                Member::Field { ref ty, .. } => types.sizes.get(ty).cloned(),
+                Member::List { ref ty, length: Some(ref length), .. } => {
+                    types.sizes.get(ty).and_then(|&s| length.evaluate(types)
+                                                            .map(|l| s * l as usize))
+                },
+                _ => None
+            }
+        }).try_fold(0, |size, member| member.map(|m| if size > m { size } else { m }))
+          .unwrap_or_else(|| panic!("The size of the union `{}` is not fixed", self.name));
+
+        types.sizes.insert(self.name.clone(), size);
+
+        writeln!(dest, "#[derive(Debug, Clone)]").unwrap();
+        writeln!(dest, "pub struct {} {{", self.name).unwrap();
+        writeln!(dest, "\t/// The {} bytes shared by the members of the union.", size).unwrap();
+        writeln!(dest, "\tpub raw: Vec<u8>,").unwrap();
+        writeln!(dest, "\t/// The byte order of the numbers stored in `raw`.").unwrap();
+        writeln!(dest, "\tpub order: ByteOrder,").unwrap();
         writeln!(dest, "}}").unwrap();
 
-        if let Some((ref mut send_impl, ref mut seqnum_sent)) = self.send_impl {
-            if self.fields.is_empty() {
-                writeln!(send_impl, "try!(socket.write_u8(0));").unwrap();
+        let scope = Scope { members: &self.members, access: "self.".to_string(), single: false,
+                            borrowed: false, parent: None };
+
+        writeln!(dest, "impl {} {{", self.name).unwrap();
+        for member in self.members.iter() {
+            let (name, ty) = match *member {
+                Member::Field { ref name, ref ty, ref enum_ty, .. } => {
+                    (name, enum_ty.as_ref().unwrap_or(ty).clone())
+                },
+                Member::List { ref name, ref ty, .. } => (name, format!("Vec<{}>", ty)),
+                _ => continue
+            };
+
+            writeln!(dest, "\t/// Decodes the content of the union as `{}`.", name).unwrap();
+            writeln!(dest, "\tpub fn {}(&self) -> IoResult<{}> {{", name, ty).unwrap();
+            writeln!(dest, "\t\tlet cursor = &mut IoCursor::new(&self.raw[..]);").unwrap();
+            writeln!(dest, "\t\tlet order = self.order;").unwrap();
+            dest.write_all(scope.member_recv(member, types).as_bytes()).unwrap();
+            writeln!(dest, "\t\tOk({})\n\t}}", name).unwrap();
+        }
+        writeln!(dest, "}}").unwrap();
+
+        writeln!(dest, r#"
+            impl SocketSend for {name} {{
+                // the raw bytes are sent as they are, whatever their byte order
+                fn socket_send<W: Write + ?Sized>(&self, socket: &mut W, _: ByteOrder) -> IoResult<()> {{
+                    socket.write_all(&self.raw)
+                }}
+
+                fn wire_size(&self) -> usize {{ self.raw.len() }}
+            }}
+
+            impl SocketRecv for {name} {{
+                fn socket_recv(cursor: &mut IoCursor<&[u8]>, order: ByteOrder) -> IoResult<{name}> {{
+                    let mut raw = vec![0; {size}];
+                    cursor.read_exact(&mut raw)?;
+                    Ok({name} {{ raw, order }})
+                }}
+            }}"#, name = self.name, size = size).unwrap();
+    }
+
+    /// Writes the `send` and `wire_size` methods of a request.
+    ///
+    /// A request starts with the opcode, a byte that contains the first member if it is one byte
+    /// long, and the length of the request in 4 bytes units. The request is then padded to a
+    /// multiple of 4 bytes.
+    ///
+    /// The requests of extensions start with the major opcode of the extension, which `send`
+    /// takes as parameter, and have their own opcode in the second byte.
+    ///
+    /// When BIG-REQUESTS is enabled, requests that are too large for a 16 bits length have a
+    /// length of 0 followed by a 32 bits length that includes these 4 additional bytes. The size
+    /// has been checked against the maximum of the server beforehand.
+    fn write_request_send<W>(&self, scope: &Scope, dest: &mut W, types: &TypesInfo, opcode: u8,
+                             extension: bool, lifetime: &str) where W: Write
+    {
+        let in_header = !extension && match self.members.first() {
+            Some(&Member::Pad(1)) => true,
+            Some(Member::Field { ty, .. }) => types.sizes.get(ty) == Some(&1),
+            _ => false
+        };
+
+        let (header, body) = if in_header {
+            (Some(&self.members[0]), &self.members[1..])
+        } else {
+            (None, &self.members[..])
+        };
+
+        writeln!(dest, "impl{} {}{} {{", lifetime, self.name, lifetime).unwrap();
+        if extension {
+            writeln!(dest, "    fn send(&self, socket: &mut BufferedStream, order: ByteOrder, \
+                                         major_opcode: u8, big_request: bool) \
+                                         -> IoResult<()> {{").unwrap();
+        } else {
+            writeln!(dest, "    fn send(&self, socket: &mut BufferedStream, order: ByteOrder, \
+                                         big_request: bool) -> IoResult<()> {{").unwrap();
+        }
+        writeln!(dest, "\t\tlet size = self.wire_size();").unwrap();
+        writeln!(dest, "\t\tlet padding = (4 - size % 4) % 4;").unwrap();
+        writeln!(dest, "\t\tlet length = (size + padding) / 4;").unwrap();
+
+        // the lengths are checked before anything is written, so that a request that can't be
+        // sent doesn't leave a part of it in the buffer
+        for member in self.members.iter() {
+            if let Member::Field { ref name, .. } = *member {
+                if let Some(Derived::ListLength(_)) = scope.derived(name) {
+                    writeln!(dest, "\t\t{};", scope.member_value(member)).unwrap();
+                }
+            }
+        }
+
+        if extension {
+            writeln!(dest, "\t\tsocket.write_u8(major_opcode)?;").unwrap();
+            writeln!(dest, "\t\tsocket.write_u8({})?;", opcode).unwrap();
+        } else {
+            writeln!(dest, "\t\tsocket.write_u8({})?;", opcode).unwrap();
+            match header {
+                Some(member) => dest.write_all(scope.member_send(member, types, false).as_bytes())
+                                    .unwrap(),
+                None => writeln!(dest, "\t\tsocket.write_u8(0)?;").unwrap(),
+            };
+        }
+        writeln!(dest, "\t\tif big_request {{ \
+                            0u16.socket_send(socket, order)?; \
+                            (length as u32 + 1).socket_send(socket, order)?; \
+                        }} else {{ \
+                            (length as u16).socket_send(socket, order)?; \
+                        }}").unwrap();
+
+        if has_align(body) {
+            writeln!(dest, "\t\tlet mut offset = 4;").unwrap();
+        }
+        for (num, member) in body.iter().enumerate() {
+            let align_follows = has_align(&body[num + 1 ..]);
+            dest.write_all(scope.member_send(member, types, align_follows).as_bytes()).unwrap();
+        }
+
+        writeln!(dest, "\t\tsocket.write_all(&[0; 3][.. padding])?;").unwrap();
+        writeln!(dest, "\t\tOk(())\n\t}}\n").unwrap();
+
+        writeln!(dest, "    fn wire_size(&self) -> usize {{").unwrap();
+        write_wire_size(dest, scope, body, 4);
+        writeln!(dest, "\t}}\n}}").unwrap();
+    }
+
+    /// Writes the implementation of `SocketSend` for the struct.
+    fn write_socket_send<W>(&self, scope: &Scope, dest: &mut W, types: &TypesInfo)
+                            where W: Write
+    {
+        writeln!(dest, "impl SocketSend for {} {{", self.name).unwrap();
+        writeln!(dest, "    fn socket_send<W: Write + ?Sized>(&self, socket: &mut W, order: ByteOrder) -> IoResult<()> {{")
+                .unwrap();
+        for code in scope.members_send(types) {
+            dest.write_all(code.as_bytes()).unwrap();
+        }
+        writeln!(dest, "\t\tOk(())\n\t}}\n").unwrap();
+
+        writeln!(dest, "    fn wire_size(&self) -> usize {{").unwrap();
+        write_wire_size(dest, scope, &self.members, 0);
+        writeln!(dest, "\t}}\n}}").unwrap();
+    }
+
+    /// Writes the implementation of `SocketRecv` for the struct, or a `socket_recv_params`
+    /// function if the struct needs parameters in order to be decoded.
+    fn write_socket_recv<W>(&self, scope: &Scope, output: &mut W, types: &mut TypesInfo)
+                            where W: Write
+    {
+        let mut params = Vec::new();
+        members_params(&self.members, &mut params);
+        params.sort();
+        params.dedup();
+
+        // the body is written first, to know whether it uses the byte order
+        let mut body = Vec::new();
+        let dest = &mut body;
+
+        if has_align(&self.members) {
+            writeln!(dest, "\t\tlet start = cursor.position();").unwrap();
+        }
+
+        match self.ty {
+            // replies start with the response type, the first member, the sequence number and
+            // the length, which some lists refer to
+            StructType::Reply => {
+                let mut refs = Vec::new();
+                members_field_refs(&self.members, &mut refs);
+
+                writeln!(dest, "\t\tcursor.set_position(cursor.position() + 1);").unwrap();
+                for (num, member) in self.members.iter().enumerate() {
+                    dest.write_all(scope.member_recv(member, types).as_bytes()).unwrap();
+                    if num != 0 {
+                        continue;
+                    }
+
+                    writeln!(dest, "\t\tcursor.set_position(cursor.position() + 2);").unwrap();
+                    if refs.iter().any(|r| r == "length") {
+                        writeln!(dest, "\t\tlet length = <CARD32 as SocketRecv>::\
+                                                                socket_recv(cursor, order)?;").unwrap();
+                    } else {
+                        writeln!(dest, "\t\tcursor.set_position(cursor.position() + 4);")
+                                .unwrap();
+                    }
+                }
+            },
+            // events start with the response type, whose highest bit indicates whether the
+            // event comes from `SendEvent`, the first member and the sequence number
+            StructType::Event { no_sequence_number } => {
+                writeln!(dest, "\t\tlet send_event = (cursor.read_u8()? & 0x80) != 0;")
+                        .unwrap();
+                for (num, member) in self.members.iter().enumerate() {
+                    dest.write_all(scope.member_recv(member, types).as_bytes()).unwrap();
+                    if num == 0 && !no_sequence_number {
+                        writeln!(dest, "\t\tcursor.set_position(cursor.position() + 2);")
+                                .unwrap();
+                    }
+                }
+            },
+
+            // generic events have a fixed header of 10 bytes, after which the members continue
+            // beyond the usual 32 bytes
+            StructType::GenericEvent => {
+                writeln!(dest, "\t\tlet send_event = (cursor.read_u8()? & 0x80) != 0;")
+                        .unwrap();
+                writeln!(dest, "\t\tcursor.set_position(cursor.position() + 9);").unwrap();
+                dest.write_all(scope.members_recv(types).as_bytes()).unwrap();
+            },
+
+            // errors start with the response type, the error code and the sequence number, and
+            // always contain the opcodes of the request at the same position
+            StructType::Error => {
+                writeln!(dest, "\t\tcursor.set_position(cursor.position() + 2);").unwrap();
+                writeln!(dest, "\t\tlet sequence = <CARD16 as SocketRecv>::\
+                                                           socket_recv(cursor, order)?;").unwrap();
+                dest.write_all(scope.members_recv(types).as_bytes()).unwrap();
+
+                let header_fields = self.header_fields(&scope.user_fields(false));
+                if header_fields.iter().any(|&(name, _, _)| name == "major_opcode") {
+                    writeln!(dest, "\t\tcursor.set_position(8);").unwrap();
+                    writeln!(dest, "\t\tlet minor_opcode = <CARD16 as SocketRecv>::\
+                                                                   socket_recv(cursor, order)?;").unwrap();
+                    writeln!(dest, "\t\tlet major_opcode = <CARD8 as SocketRecv>::\
+                                                                   socket_recv(cursor, order)?;").unwrap();
+                }
+
+                // named by `XError::decode`, which knows the extensions of the connection
+                writeln!(dest, "\t\tlet request = None;").unwrap();
+            },
+
+            _ => dest.write_all(scope.members_recv(types).as_bytes()).unwrap(),
+        }
+
+        let header_fields = self.header_fields(&scope.user_fields(false));
+        let header_fields: Vec<_> = header_fields.iter().map(|&(name, _, _)| name).collect();
+        writeln!(dest, "\t\tOk({})\n\t}}\n}}", scope.construct(&self.name, &header_fields))
+                .unwrap();
+
+        let uses_order = String::from_utf8_lossy(&body).contains("cursor, order");
+        let order = if uses_order { "order" } else { "_" };
+        if params.is_empty() {
+            writeln!(output, "impl SocketRecv for {} {{", self.name).unwrap();
+            writeln!(output, "    fn socket_recv(cursor: &mut IoCursor<&[u8]>, {}: ByteOrder) \
+                                                 -> IoResult<{}> {{",
+                     order, self.name).unwrap();
+        } else {
+            let params_decl = params.iter().map(|p| format!(", {}: usize", p))
+                                    .collect::<Vec<_>>().concat();
+            writeln!(output, "impl {} {{", self.name).unwrap();
+            writeln!(output, "    fn socket_recv_params(cursor: &mut IoCursor<&[u8]>, \
+                                                        {}: ByteOrder{}) -> IoResult<{}> {{",
+                     order, params_decl, self.name).unwrap();
+        }
+        output.write_all(&body).unwrap();
+
+        if !params.is_empty() {
+            types.struct_params.insert(self.name.clone(), params);
+        }
+    }
+}
+
+impl<'a> Scope<'a> {
+    /// Returns the code that gives access to a member when sending.
+    fn access(&self, name: &str) -> String {
+        if self.single {
+            self.access.clone()
+        } else {
+            format!("{}{}", self.access, name)
+        }
+    }
+
+    /// Returns the scope of the members of a case, whose value is in the `value` variable.
+    fn case_scope<'b>(&'b self, case: &'b Case) -> Scope<'b> {
+        Scope {
+            members: &case.members,
+            access: if case.ty.is_some() { "value.".to_string() } else { "value".to_string() },
+            single: case.ty.is_none(),
+            borrowed: false,
+            parent: Some(self),
+        }
+    }
+
+    /// Returns the field with the given name.
+    fn field(&self, field: &str) -> Option<&'a Member> {
+        self.members.iter().find(|m| match **m {
+            Member::Field { ref name, .. } => name == field,
+            _ => false
+        })
+    }
+
+    /// Returns the enum of the `altenum` attribute of a field.
+    fn altenum(&self, field: &str) -> Option<&'a str> {
+        match self.field(field) {
+            Some(&Member::Field { altenum: Some(ref altenum), .. }) => Some(&altenum[..]),
+            _ => None
+        }
+    }
+
+    /// If the field is computed from another member, returns this member.
+    fn derived(&self, field: &str) -> Option<Derived<'a>> {
+        let is_enum = matches!(self.field(field), Some(&Member::Field { enum_ty: Some(_), .. }));
+
+        for member in self.members.iter() {
+            match *member {
+                Member::List { ref name, length: Some(Expression::FieldRef(ref len)), .. }
+                    if len == field && !is_enum => return Some(Derived::ListLength(name)),
+
+                Member::Switch(ref switch)
+                    if switch.expression == Expression::FieldRef(field.to_string()) &&
+                       switch.cases.iter().all(|c| c.bitcase) =>
+                {
+                    return Some(Derived::SwitchMask(switch));
+                },
+
+                _ => ()
             }
+        }
 
-            if !*seqnum_sent {
-                writeln!(send_impl, "try!(sequence.socket_send(socket));").unwrap();
-                *seqnum_sent = true;
+        None
+    }
+
+    /// Returns the list of (name, type) of the fields of the generated struct.
+    ///
+    /// The fields that contain the length of a list or the mask of a switch are not included,
+    /// as they are computed from the list or the switch.
+    fn user_fields(&self, is_request: bool) -> Vec<(String, String)> {
+        self.members.iter().filter_map(|member| {
+            match *member {
+                Member::Field { ref name, .. } if self.derived(name).is_some() => None,
+                Member::Field { ref name, ref ty, ref enum_ty, .. } => {
+                    Some((name.clone(), enum_ty.as_ref().unwrap_or(ty).clone()))
+                },
+                Member::List { ref name, ref ty, .. } if is_request => {
+                    Some((name.clone(), format!("&[{}]", ty)))
+                },
+                Member::List { ref name, ref ty, .. } => {
+                    Some((name.clone(), format!("Vec<{}>", ty)))
+                },
+                Member::Switch(ref switch) if is_request => {
+                    Some((switch.name.clone(), format!("&{}", switch.ty)))
+                },
+                Member::Switch(ref switch) => Some((switch.name.clone(), switch.ty.clone())),
+                _ => None
             }
+        }).collect()
+    }
+
+    /// Returns the code that builds a struct from the local variables of the members and of the
+    /// additional fields.
+    fn construct(&self, ty: &str, extra_fields: &[&str]) -> String {
+        let names = extra_fields.iter().map(|f| f.to_string())
+                                .chain(self.user_fields(false).into_iter().map(|(name, _)| name));
+        let fields = names.map(|name| format!(" {},", name)).collect::<Vec<_>>().concat();
+
+        format!("{} {{{} }}", ty, fields)
+    }
+
+    /// Returns the code that gives the value of a field as a `usize`.
+    fn field_value(&self, field: &str, context: Context) -> String {
+        match (self.field(field), context) {
+            (Some(&Member::Field { enum_ty: Some(_), .. }), Context::Recv) => {
+                format!("({}.to_raw() as usize)", field)
+            },
+            (Some(_), Context::Recv) => format!("({} as usize)", field),
+            (Some(Member::Field { enum_ty, .. }), Context::Send) => {
+                match (self.derived(field), enum_ty) {
+                    (Some(Derived::ListLength(list)), _) => format!("{}.len()", self.access(list)),
+                    (Some(Derived::SwitchMask(switch)), _) => {
+                        format!("({}.value_mask() as usize)", self.access(&switch.name))
+                    },
+                    (None, &Some(_)) => format!("({}.to_raw() as usize)", self.access(field)),
+                    (None, &None) => format!("({} as usize)", self.access(field)),
+                }
+            },
+            (Some(_), Context::Send) => unreachable!(),
+
+            // the members of a case can refer to the fields that are outside of the switch
+            (None, _) if self.parent.is_some() => {
+                self.parent.unwrap().field_value(field, context)
+            },
 
-            writeln!(send_impl, "Ok(()) }} }}").unwrap();
-            dest.write_all(send_impl).unwrap();
+            // requests can refer to the length of a list with `<fieldref>list_len</fieldref>`
+            (None, Context::Send) if field.ends_with("_len") => {
+                format!("{}.len()", self.access(&field[.. field.len() - 4]))
+            },
+            (None, Context::Send) => format!("({} as usize)", self.access(field)),
+            (None, Context::Recv) => format!("({} as usize)", field),
         }
+    }
 
-        if let Some(ref mut socket_send_impl) = self.socket_send_impl {
-            writeln!(socket_send_impl, "\t\tOk(())\n\t}}\n}}").unwrap();
-            dest.write_all(socket_send_impl).unwrap();
+    /// Returns the code that gives the value that must be sent for a field. The lengths of the
+    /// lists are converted with `?`, which fails if they don't fit in the field.
+    fn member_value(&self, member: &Member) -> String {
+        match *member {
+            Member::Field { ref name, ref ty, ref enum_ty, .. } => {
+                match (self.derived(name), enum_ty) {
+                    (Some(Derived::ListLength(list)), _) => {
+                        format!("{}::try_from({}.len()).map_err(|_| list_too_long(\"{}\"))?",
+                                ty, self.access(list), list)
+                    },
+                    (Some(Derived::SwitchMask(switch)), _) => {
+                        format!("({}.value_mask() as {})", self.access(&switch.name), ty)
+                    },
+                    (None, &Some(_)) => format!("({}.to_raw() as {})", self.access(name), ty),
+                    (None, &None) => self.access(name),
+                }
+            },
+            _ => unreachable!()
         }
+    }
+
+    /// Returns the code that adds the number of bytes of a member on the wire to the variable
+    /// `var`, which must contain the number of bytes before the member.
+    fn member_size(&self, member: &Member, var: &str) -> String {
+        match *member {
+            Member::Pad(bytes) => format!("\t\t{} += {};\n", var, bytes),
+            Member::Align(align) => {
+                format!("\t\t{var} += ({align} - {var} % {align}) % {align};\n",
+                        var = var, align = align)
+            },
+            // the fields computed from other members are numbers, whose size is fixed
+            Member::Field { ref name, ref ty, .. } if self.derived(name).is_some() => {
+                format!("\t\t{} += ::std::mem::size_of::<{}>();\n", var, ty)
+            },
+            Member::Field { .. } => {
+                format!("\t\t{} += {}.wire_size();\n", var, self.member_value(member))
+            },
+            Member::ExprField { ref ty, .. } => {
+                format!("\t\t{} += ::std::mem::size_of::<{}>();\n", var, ty)
+            },
+            Member::List { ref name, ref ty, .. } if is_byte(ty) => {
+                format!("\t\t{} += {}.len();\n", var, self.access(name))
+            },
+            Member::List { ref name, .. } => {
+                format!("\t\t{} += {}.iter().fold(0, |size, elem| size + elem.wire_size());\n",
+                        var, self.access(name))
+            },
+            Member::Switch(ref switch) => {
+                let mut code = String::new();
+                for case in switch.cases.iter() {
+                    let scope = self.case_scope(case);
+                    code.push_str(&format!("\t\tif let Some(ref value) = {}.{} {{\n",
+                                           self.access(&switch.name), case.name));
+                    for member in case.members.iter() {
+                        code.push_str(&scope.member_size(member, var));
+                    }
+                    code.push_str("\t\t}\n");
+                }
+                code
+            },
+        }
+    }
+
+    /// Returns the code that sends each member to the socket, one entry per member.
+    fn members_send(&self, types: &TypesInfo) -> Vec<String> {
+        let track_offset = has_align(self.members);
+
+        let mut result = Vec::with_capacity(self.members.len());
+
+        for (num, member) in self.members.iter().enumerate() {
+            let mut code = if track_offset && num == 0 {
+                "\t\tlet mut offset = 0;\n".to_string()
+            } else {
+                String::new()
+            };
+
+            let align_follows = has_align(&self.members[num + 1 ..]);
+            code.push_str(&self.member_send(member, types, align_follows));
+            result.push(code);
+        }
+
+        result
+    }
+
+    /// Returns the code that sends a member to the socket. If `track_offset` is true, because
+    /// the members that follow are aligned, the code also adds the size of the member to the
+    /// `offset` variable.
+    fn member_send(&self, member: &Member, types: &TypesInfo, track_offset: bool) -> String {
+        let mut code = match *member {
+            Member::Pad(bytes) => format!("\t\tsocket.write_all(&[0; {}])?;\n", bytes),
+            Member::Align(align) => {
+                format!("\t\tsocket.write_all(&[0; {align}][.. ({align} - offset % {align}) \
+                                                                 % {align}])?;\n",
+                        align = align)
+            },
+            Member::Field { .. } => {
+                format!("\t\t{}.socket_send(socket, order)?;\n", self.member_value(member))
+            },
+            Member::ExprField { ref ty, ref expression, .. } => {
+                let context = ScopeContext { scope: self, types, context: Context::Send };
+                let value = expression.to_code(&context);
+
+                if ty == "BOOL" {
+                    format!("\t\t({} != 0).socket_send(socket, order)?;\n", value)
+                } else {
+                    format!("\t\t(({}) as {}).socket_send(socket, order)?;\n", value, ty)
+                }
+            },
+            // lists of bytes are written at once, so that large ones aren't copied
+            Member::List { ref name, ref ty, .. } if is_byte(ty) => {
+                // the `value` of a case with a single member is already a reference
+                if self.borrowed || self.single {
+                    format!("\t\tsocket.write_all({})?;\n", self.access(name))
+                } else {
+                    format!("\t\tsocket.write_all(&{})?;\n", self.access(name))
+                }
+            },
+            Member::List { ref name, .. } => {
+                format!("\t\tfor elem in {}.iter() {{ elem.socket_send(socket, order)?; }}\n",
+                        self.access(name))
+            },
 
-        self.fields
+            // the members of the cases keep track of the offset by themselves
+            Member::Switch(ref switch) => {
+                let mut code = String::new();
+                for case in switch.cases.iter() {
+                    let scope = self.case_scope(case);
+                    code.push_str(&format!("\t\tif let Some(ref value) = {}.{} {{\n",
+                                           self.access(&switch.name), case.name));
+                    for (num, member) in case.members.iter().enumerate() {
+                        let align_follows = has_align(&case.members[num + 1 ..]);
+                        code.push_str(&scope.member_send(member, types,
+                                                         track_offset || align_follows));
+                    }
+                    code.push_str("\t\t}\n");
+                }
+                return code;
+            },
+        };
+
+        // keeping track of the offset in order to handle `<pad align="N" />`
+        if track_offset {
+            code.push_str(&self.member_size(member, "offset"));
+        }
+
+        code
+    }
+
+    /// Returns the code that reads all the members into local variables.
+    fn members_recv(&self, types: &TypesInfo) -> String {
+        self.members.iter().map(|m| self.member_recv(m, types)).collect::<Vec<_>>().concat()
+    }
+
+    /// Returns the code that reads a member from the cursor into a local variable.
+    fn member_recv(&self, member: &Member, types: &TypesInfo) -> String {
+        let context = ScopeContext { scope: self, types, context: Context::Recv };
+
+        match *member {
+            Member::Pad(bytes) => {
+                format!("\t\tcursor.set_position(cursor.position() + {});\n", bytes)
+            },
+            Member::Align(align) => {
+                format!("\t\t{{ let offset = (cursor.position() - start) as usize; \
+                                cursor.set_position(cursor.position() + \
+                                                    (({} - offset % {}) % {}) as u64); }}\n",
+                        align, align, align)
+            },
+            Member::Field { ref name, ref ty, enum_ty: Some(ref enum_ty), .. } => {
+                // `from_raw` takes the value as a `u32`
+                let raw = if ty == "CARD32" { "raw" } else { "raw as u32" };
+                format!("\t\tlet {name} = {{ let raw = <{ty} as SocketRecv>::socket_recv(cursor, order)?; \
+                                {en}::from_raw({raw}) }};\n",
+                        name = name, ty = ty, en = enum_ty, raw = raw)
+            },
+            Member::Field { ref name, ref ty, .. } | Member::ExprField { ref name, ref ty, .. } => {
+                format!("\t\tlet {} = <{} as SocketRecv>::socket_recv(cursor, order)?;\n", name, ty)
+            },
+            Member::List { ref name, ref ty, ref length } => {
+                let elem = match types.struct_params.get(ty) {
+                    Some(params) => {
+                        let params = params.iter().map(|p| context.field(p))
+                                           .collect::<Vec<_>>().join(", ");
+                        format!("{}::socket_recv_params(cursor, order, {})?", ty, params)
+                    },
+                    None => format!("<{} as SocketRecv>::socket_recv(cursor, order)?", ty),
+                };
+
+                match (length, &ty[..]) {
+                    (Some(length), "u8") => {
+                        format!("\t\tlet {} = {{ let mut list = vec![0; {}]; \
+                                                cursor.read_exact(&mut list)?; list }};\n",
+                                name, without_parens(&length.to_code(&context)))
+                    },
+                    (Some(length), _) => {
+                        format!("\t\tlet {} = {{ let len = {}; \
+                                                let mut list = Vec::with_capacity(len); \
+                                                for _ in 0 .. len {{ list.push({}); }} list }};\n",
+                                name, without_parens(&length.to_code(&context)), elem)
+                    },
+                    (&None, "u8") => {
+                        format!("\t\tlet {} = {{ let mut list = Vec::new(); \
+                                                cursor.read_to_end(&mut list)?; list }};\n",
+                                name)
+                    },
+                    (&None, _) => {
+                        format!("\t\tlet {} = {{ let mut list = Vec::new(); \
+                                                while (cursor.position() as usize) < \
+                                                      cursor.get_ref().len() \
+                                                {{ list.push({}); }} list }};\n",
+                                name, elem)
+                    },
+                }
+            },
+            Member::Switch(ref switch) => {
+                let mut code = format!("\t\tlet {} = {{\n\t\tlet switch_expr = {};\n",
+                                       switch.name,
+                                       without_parens(&switch.expression.to_code(&context)));
+
+                for case in switch.cases.iter() {
+                    let values: Vec<_> = case.values.iter().map(|v| v.to_code(&context)).collect();
+                    let condition = if case.bitcase {
+                        format!("(switch_expr & ({})) != 0", values.join(" | "))
+                    } else {
+                        values.iter().map(|v| format!("switch_expr == {}", v))
+                              .collect::<Vec<_>>().join(" || ")
+                    };
+
+                    let scope = self.case_scope(case);
+                    let value = match case.ty {
+                        Some(ref ty) => scope.construct(ty, &[]),
+                        None => scope.user_fields(false).remove(0).0,
+                    };
+
+                    code.push_str(&format!("\t\tlet {} = if {} {{\n{}\t\tSome({})\n\t\t}} \
+                                            else {{ None }};\n",
+                                           case.name, condition, scope.members_recv(types),
+                                           value));
+                }
+
+                let fields = switch.cases.iter().map(|c| format!(" {},", c.name))
+                                   .collect::<Vec<_>>().concat();
+                code.push_str(&format!("\t\t{} {{{} }}\n\t\t}};\n", switch.ty, fields));
+                code
+            },
+        }
+    }
+}
+
+/// Returns true if `ty` is a single byte, which allows sending the lists of `ty` as a slice.
+fn is_byte(ty: &str) -> bool {
+    ty == "u8" || ty == "BYTE" || ty == "CARD8"
+}
+
+/// Writes the struct that holds the content of a switch, and the structs of its cases.
+fn write_switch_types<W>(switch: &Switch, dest: &mut W, types: &TypesInfo) where W: Write {
+    let mut case_fields = Vec::new();
+
+    for case in switch.cases.iter() {
+        for member in case.members.iter() {
+            if let Member::Switch(ref switch) = *member {
+                write_switch_types(switch, dest, types);
+            }
+        }
+
+        let scope = Scope { members: &case.members, access: String::new(), single: false,
+                            borrowed: false, parent: None };
+        let fields = scope.user_fields(false);
+
+        match case.ty {
+            Some(ref ty) => {
+                writeln!(dest, "#[derive(Debug, Clone)]").unwrap();
+                writeln!(dest, "pub struct {} {{", ty).unwrap();
+                for (name, field_ty) in fields.iter() {
+                    writeln!(dest, "\tpub {}: {},", name, field_ty).unwrap();
+                }
+                writeln!(dest, "}}").unwrap();
+
+                case_fields.push((case.name.clone(), ty.clone()));
+            },
+            None => {
+                let (_, ty) = fields.into_iter().next().unwrap();
+                case_fields.push((case.name.clone(), ty));
+            },
+        }
+    }
+
+    writeln!(dest, "/// Values of the `{}` switch, each of them being optional.", switch.name)
+            .unwrap();
+    writeln!(dest, "#[derive(Debug, Clone, Default)]").unwrap();
+    writeln!(dest, "pub struct {} {{", switch.ty).unwrap();
+    for (name, ty) in case_fields.iter() {
+        writeln!(dest, "\tpub {}: Option<{}>,", name, ty).unwrap();
+    }
+    writeln!(dest, "}}").unwrap();
+
+    writeln!(dest, "impl {} {{", switch.ty).unwrap();
+    writeln!(dest, "\tpub fn new() -> {} {{ Default::default() }}", switch.ty).unwrap();
+    for (name, ty) in case_fields.iter() {
+        writeln!(dest, "\tpub fn {name}(mut self, value: {ty}) -> {sw} {{ \
+                            self.{name} = Some(value); self }}",
+                 name = name, ty = ty, sw = switch.ty).unwrap();
+    }
+
+    // the mask of a switch that only contains bitcases is computed from the values present
+    if switch.cases.iter().all(|c| c.bitcase) {
+        writeln!(dest, "\t/// Returns the mask that corresponds to the values that are present.")
+                .unwrap();
+        writeln!(dest, "\tpub fn value_mask(&self) -> u32 {{").unwrap();
+        writeln!(dest, "\t\tlet mut mask = 0;").unwrap();
+        for case in switch.cases.iter() {
+            let value = case.values.iter().map(|v| v.evaluate(types).unwrap())
+                            .fold(0, |a, b| a | b);
+            writeln!(dest, "\t\tif self.{}.is_some() {{ mask |= {}; }}", case.name, value).unwrap();
+        }
+        writeln!(dest, "\t\tmask\n\t}}").unwrap();
+    }
+
+    writeln!(dest, "}}").unwrap();
+}
+
+/// Sorts the bitcases of the switches in the order of their bits, which is the order in which
+/// their values are on the wire.
+fn sort_bitcases(members: &mut [Member], types: &TypesInfo) {
+    for member in members.iter_mut() {
+        if let Member::Switch(ref mut switch) = *member {
+            for case in switch.cases.iter_mut() {
+                sort_bitcases(&mut case.members, types);
+            }
+
+            if switch.cases.iter().all(|c| c.bitcase && c.values.len() == 1 &&
+                                           c.values[0].evaluate(types).is_some())
+            {
+                switch.cases.sort_by_key(|c| c.values[0].evaluate(types).unwrap());
+            }
+        }
+    }
+}
+
+/// Returns true if some members, including the members of the switches, must be aligned.
+/// Writes the body of a `wire_size` method, which adds the size of the members to the size of
+/// the header.
+fn write_wire_size<W>(dest: &mut W, scope: &Scope, members: &[Member], header: usize)
+                      where W: Write
+{
+    if members.is_empty() {
+        writeln!(dest, "\t\t{}", header).unwrap();
+        return;
+    }
+
+    writeln!(dest, "\t\tlet mut size = {};", header).unwrap();
+    for member in members.iter() {
+        dest.write_all(scope.member_size(member, "size").as_bytes()).unwrap();
+    }
+    writeln!(dest, "\t\tsize").unwrap();
+}
+
+fn has_align(members: &[Member]) -> bool {
+    members.iter().any(|m| match *m {
+        Member::Align(_) => true,
+        Member::Switch(ref switch) => switch.cases.iter().any(|c| has_align(&c.members)),
+        _ => false
+    })
+}
+
+/// Adds to `output` the names of the parameters that must be passed when decoding the members.
+fn members_params(members: &[Member], output: &mut Vec<String>) {
+    for member in members.iter() {
+        match *member {
+            Member::List { length: Some(ref length), .. } => length.param_refs(output),
+            Member::ExprField { ref expression, .. } => expression.param_refs(output),
+            Member::Switch(ref switch) => {
+                switch.expression.param_refs(output);
+                for case in switch.cases.iter() {
+                    members_params(&case.members, output);
+                }
+            },
+            _ => ()
+        }
+    }
+}
+
+/// Adds to `output` the names of the fields that the members refer to.
+fn members_field_refs(members: &[Member], output: &mut Vec<String>) {
+    for member in members.iter() {
+        match *member {
+            Member::List { length: Some(ref length), .. } => length.field_refs(output),
+            Member::ExprField { ref expression, .. } => expression.field_refs(output),
+            Member::Switch(ref switch) => {
+                switch.expression.field_refs(output);
+                for case in switch.cases.iter() {
+                    members_field_refs(&case.members, output);
+                }
+            },
+            _ => ()
+        }
+    }
+}
+
+/// Parses the expression that is the content of an element, if any. Consumes the end of the
+/// element.
+fn parse_optional_expression<R>(events: &mut EventReader<R>) -> Option<Expression>
+                                where R: Read
+{
+    let mut expression = None;
+
+    loop {
+        match recv(events) {
+            XmlEvent::StartElement{ref name, ref attributes, ..}
+                if Expression::is_expression(&name.local_name) =>
+            {
+                assert!(expression.is_none());
+                expression = Some(Expression::parse(&name.local_name, attributes, events));
+            },
+
+            XmlEvent::StartElement{..} => skip_element(events),
+            XmlEvent::EndElement{..} => return expression,
+            _ => ()
+        }
     }
 }
@@ -0,0 +1,250 @@
+//! The `<valueparam>` elements of the XML definitions only give the name of the mask and of the
+//! list, but not the types of the values. Newer versions of the definitions describe them with a
+//! `<switch>` instead, which we provide here for the requests of the core protocol.
+
+/// Returns the content of the `<switch>` that replaces the `<valueparam>` of a request, if known.
+pub fn switch(request: &str) -> Option<&'static str> {
+    match request {
+        "CreateWindow" | "ChangeWindowAttributes" => Some(WINDOW_ATTRIBUTES),
+        "ConfigureWindow" => Some(CONFIGURE_WINDOW),
+        "CreateGC" | "ChangeGC" => Some(GC_ATTRIBUTES),
+        "ChangeKeyboardControl" => Some(KEYBOARD_CONTROL),
+        _ => None
+    }
+}
+
+const WINDOW_ATTRIBUTES: &str = r#"
+<switch name="value_list">
+    <fieldref>value_mask</fieldref>
+    <bitcase>
+        <enumref ref="CW">BackPixmap</enumref>
+        <field type="PIXMAP" name="background_pixmap" altenum="BackPixmap" />
+    </bitcase>
+    <bitcase>
+        <enumref ref="CW">BackPixel</enumref>
+        <field type="CARD32" name="background_pixel" />
+    </bitcase>
+    <bitcase>
+        <enumref ref="CW">BorderPixmap</enumref>
+        <field type="PIXMAP" name="border_pixmap" altenum="Pixmap" />
+    </bitcase>
+    <bitcase>
+        <enumref ref="CW">BorderPixel</enumref>
+        <field type="CARD32" name="border_pixel" />
+    </bitcase>
+    <bitcase>
+        <enumref ref="CW">BitGravity</enumref>
+        <field type="CARD32" name="bit_gravity" enum="Gravity" />
+    </bitcase>
+    <bitcase>
+        <enumref ref="CW">WinGravity</enumref>
+        <field type="CARD32" name="win_gravity" enum="Gravity" />
+    </bitcase>
+    <bitcase>
+        <enumref ref="CW">BackingStore</enumref>
+        <field type="CARD32" name="backing_store" enum="BackingStore" />
+    </bitcase>
+    <bitcase>
+        <enumref ref="CW">BackingPlanes</enumref>
+        <field type="CARD32" name="backing_planes" />
+    </bitcase>
+    <bitcase>
+        <enumref ref="CW">BackingPixel</enumref>
+        <field type="CARD32" name="backing_pixel" />
+    </bitcase>
+    <bitcase>
+        <enumref ref="CW">OverrideRedirect</enumref>
+        <field type="CARD32" name="override_redirect" />
+    </bitcase>
+    <bitcase>
+        <enumref ref="CW">SaveUnder</enumref>
+        <field type="CARD32" name="save_under" />
+    </bitcase>
+    <bitcase>
+        <enumref ref="CW">EventMask</enumref>
+        <field type="CARD32" name="event_mask" mask="EventMask" />
+    </bitcase>
+    <bitcase>
+        <enumref ref="CW">DontPropagate</enumref>
+        <field type="CARD32" name="do_not_propogate_mask" mask="EventMask" />
+    </bitcase>
+    <bitcase>
+        <enumref ref="CW">Colormap</enumref>
+        <field type="COLORMAP" name="colormap" altenum="Colormap" />
+    </bitcase>
+    <bitcase>
+        <enumref ref="CW">Cursor</enumref>
+        <field type="CURSOR" name="cursor" altenum="Cursor" />
+    </bitcase>
+</switch>
+"#;
+
+const CONFIGURE_WINDOW: &str = r#"
+<switch name="value_list">
+    <fieldref>value_mask</fieldref>
+    <bitcase>
+        <enumref ref="ConfigWindow">X</enumref>
+        <field type="INT32" name="x" />
+    </bitcase>
+    <bitcase>
+        <enumref ref="ConfigWindow">Y</enumref>
+        <field type="INT32" name="y" />
+    </bitcase>
+    <bitcase>
+        <enumref ref="ConfigWindow">Width</enumref>
+        <field type="CARD32" name="width" />
+    </bitcase>
+    <bitcase>
+        <enumref ref="ConfigWindow">Height</enumref>
+        <field type="CARD32" name="height" />
+    </bitcase>
+    <bitcase>
+        <enumref ref="ConfigWindow">BorderWidth</enumref>
+        <field type="CARD32" name="border_width" />
+    </bitcase>
+    <bitcase>
+        <enumref ref="ConfigWindow">Sibling</enumref>
+        <field type="WINDOW" name="sibling" altenum="Window" />
+    </bitcase>
+    <bitcase>
+        <enumref ref="ConfigWindow">StackMode</enumref>
+        <field type="CARD32" name="stack_mode" enum="StackMode" />
+    </bitcase>
+</switch>
+"#;
+
+const GC_ATTRIBUTES: &str = r#"
+<switch name="value_list">
+    <fieldref>value_mask</fieldref>
+    <bitcase>
+        <enumref ref="GC">Function</enumref>
+        <field type="CARD32" name="function" enum="GX" />
+    </bitcase>
+    <bitcase>
+        <enumref ref="GC">PlaneMask</enumref>
+        <field type="CARD32" name="plane_mask" />
+    </bitcase>
+    <bitcase>
+        <enumref ref="GC">Foreground</enumref>
+        <field type="CARD32" name="foreground" />
+    </bitcase>
+    <bitcase>
+        <enumref ref="GC">Background</enumref>
+        <field type="CARD32" name="background" />
+    </bitcase>
+    <bitcase>
+        <enumref ref="GC">LineWidth</enumref>
+        <field type="CARD32" name="line_width" />
+    </bitcase>
+    <bitcase>
+        <enumref ref="GC">LineStyle</enumref>
+        <field type="CARD32" name="line_style" enum="LineStyle" />
+    </bitcase>
+    <bitcase>
+        <enumref ref="GC">CapStyle</enumref>
+        <field type="CARD32" name="cap_style" enum="CapStyle" />
+    </bitcase>
+    <bitcase>
+        <enumref ref="GC">JoinStyle</enumref>
+        <field type="CARD32" name="join_style" enum="JoinStyle" />
+    </bitcase>
+    <bitcase>
+        <enumref ref="GC">FillStyle</enumref>
+        <field type="CARD32" name="fill_style" enum="FillStyle" />
+    </bitcase>
+    <bitcase>
+        <enumref ref="GC">FillRule</enumref>
+        <field type="CARD32" name="fill_rule" enum="FillRule" />
+    </bitcase>
+    <bitcase>
+        <enumref ref="GC">Tile</enumref>
+        <field type="PIXMAP" name="tile" altenum="Pixmap" />
+    </bitcase>
+    <bitcase>
+        <enumref ref="GC">Stipple</enumref>
+        <field type="PIXMAP" name="stipple" altenum="Pixmap" />
+    </bitcase>
+    <bitcase>
+        <enumref ref="GC">TileStippleOriginX</enumref>
+        <field type="INT32" name="tile_stipple_x_origin" />
+    </bitcase>
+    <bitcase>
+        <enumref ref="GC">TileStippleOriginY</enumref>
+        <field type="INT32" name="tile_stipple_y_origin" />
+    </bitcase>
+    <bitcase>
+        <enumref ref="GC">Font</enumref>
+        <field type="FONT" name="font" altenum="Font" />
+    </bitcase>
+    <bitcase>
+        <enumref ref="GC">SubwindowMode</enumref>
+        <field type="CARD32" name="subwindow_mode" enum="SubwindowMode" />
+    </bitcase>
+    <bitcase>
+        <enumref ref="GC">GraphicsExposures</enumref>
+        <field type="CARD32" name="graphics_exposures" />
+    </bitcase>
+    <bitcase>
+        <enumref ref="GC">ClipOriginX</enumref>
+        <field type="INT32" name="clip_x_origin" />
+    </bitcase>
+    <bitcase>
+        <enumref ref="GC">ClipOriginY</enumref>
+        <field type="INT32" name="clip_y_origin" />
+    </bitcase>
+    <bitcase>
+        <enumref ref="GC">ClipMask</enumref>
+        <field type="PIXMAP" name="clip_mask" altenum="Pixmap" />
+    </bitcase>
+    <bitcase>
+        <enumref ref="GC">DashOffset</enumref>
+        <field type="CARD32" name="dash_offset" />
+    </bitcase>
+    <bitcase>
+        <enumref ref="GC">DashList</enumref>
+        <field type="CARD32" name="dashes" />
+    </bitcase>
+    <bitcase>
+        <enumref ref="GC">ArcMode</enumref>
+        <field type="CARD32" name="arc_mode" enum="ArcMode" />
+    </bitcase>
+</switch>
+"#;
+
+const KEYBOARD_CONTROL: &str = r#"
+<switch name="value_list">
+    <fieldref>value_mask</fieldref>
+    <bitcase>
+        <enumref ref="KB">KeyClickPercent</enumref>
+        <field type="INT32" name="key_click_percent" />
+    </bitcase>
+    <bitcase>
+        <enumref ref="KB">BellPercent</enumref>
+        <field type="INT32" name="bell_percent" />
+    </bitcase>
+    <bitcase>
+        <enumref ref="KB">BellPitch</enumref>
+        <field type="INT32" name="bell_pitch" />
+    </bitcase>
+    <bitcase>
+        <enumref ref="KB">BellDuration</enumref>
+        <field type="INT32" name="bell_duration" />
+    </bitcase>
+    <bitcase>
+        <enumref ref="KB">Led</enumref>
+        <field type="CARD32" name="led" />
+    </bitcase>
+    <bitcase>
+        <enumref ref="KB">LedMode</enumref>
+        <field type="CARD32" name="led_mode" enum="LedMode" />
+    </bitcase>
+    <bitcase>
+        <enumref ref="KB">Key</enumref>
+        <field type="CARD32" name="key" />
+    </bitcase>
+    <bitcase>
+        <enumref ref="KB">AutoRepeatMode</enumref>
+        <field type="CARD32" name="auto_repeat_mode" enum="AutoRepeatMode" />
+    </bitcase>
+</switch>
+"#;
@@ -0,0 +1,289 @@
+//! Integration with asynchronous code.
+//!
+//! Reply handles are futures and `Events` is a stream. They are woken up whenever the packets
+//! they wait for are received, which requires something to read from the socket: one of the
+//! drivers of this module, or a custom event loop calling `read_available`.
+//!
+//! Creating a driver switches the socket to non-blocking mode, so that the futures can send
+//! their requests without blocking. What they can't send right away is sent by the driver once
+//! the socket is writable.
+
+use std::future::Future;
+use std::io::{Error as IoError, ErrorKind};
+use std::io::Result as IoResult;
+use std::mem;
+use std::pin::Pin;
+use std::task::{Context, Poll, Waker};
+
+#[cfg(all(unix, any(feature = "with-tokio", feature = "with-async-std")))]
+use std::os::unix::io::RawFd;
+
+use {Event, ReplyHandle, XConnection, XError};
+#[cfg(all(unix, any(feature = "with-tokio", feature = "with-async-std")))]
+use stream::Stream;
+
+/// The asynchronous tasks waiting for packets from the server.
+pub struct WaitingTasks {
+    wakers: Vec<Waker>,
+    /// The driver, to wake up when requests are left in the output buffer.
+    driver: Option<Waker>,
+}
+
+impl WaitingTasks {
+    pub fn new() -> WaitingTasks {
+        WaitingTasks {
+            wakers: Vec::new(),
+            driver: None,
+        }
+    }
+
+    /// Adds a task to wake up when packets are received or when the connection is lost.
+    ///
+    /// Tasks must register before checking whether what they wait for has been received,
+    /// otherwise they could miss the wake up.
+    pub fn register(&mut self, waker: &Waker) {
+        if !self.wakers.iter().any(|w| w.will_wake(waker)) {
+            self.wakers.push(waker.clone());
+        }
+    }
+
+    /// Removes the tasks to wake up after packets have been received.
+    pub fn take(&mut self) -> Vec<Waker> {
+        mem::take(&mut self.wakers)
+    }
+}
+
+impl XConnection {
+    /// Returns the next event if one has been received, otherwise arranges for the current
+    /// task to be woken up when one is.
+    ///
+    /// Returns `Ready(None)` if the connection to the server has been lost.
+    pub fn poll_next_event(&self, cx: &mut Context) -> Poll<Option<Event>> {
+        self.tasks.lock().unwrap().register(cx.waker());
+
+        if let Some(event) = self.poll_for_queued_event() {
+            return Poll::Ready(Some(event));
+        }
+
+        if self.reader.lock().unwrap().is_disconnected() || self.try_flush().is_err() {
+            return Poll::Ready(None);
+        }
+
+        Poll::Pending
+    }
+
+    /// Sends the requests of the output buffer, for as long as it doesn't block.
+    ///
+    /// If some are left, the driver is woken up to send them once the socket is writable. This
+    /// blocks like `flush` if there is no driver, as the socket is then in blocking mode.
+    fn try_flush(&self) -> IoResult<()> {
+        if !self.socket.lock().unwrap().try_flush()? {
+            if let Some(driver) = self.tasks.lock().unwrap().driver.take() {
+                driver.wake();
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the file descriptor of the socket, after switching it to non-blocking mode.
+    ///
+    /// Streams that can't be switched stay in blocking mode, in which case sending the
+    /// requests of the futures blocks.
+    #[cfg(all(unix, any(feature = "with-tokio", feature = "with-async-std")))]
+    fn driver_fd(&self) -> IoResult<RawFd> {
+        let fd = match self.raw_fd {
+            Some(fd) => fd,
+            None => return Err(IoError::other("The stream has no file descriptor")),
+        };
+
+        let _ = self.socket.lock().unwrap().set_nonblocking(true);
+        Ok(fd)
+    }
+
+    /// Registers the driver to wake up when requests are left in the output buffer, and sends
+    /// as many of them as possible. Returns true if some are left.
+    #[cfg(all(unix, any(feature = "with-tokio", feature = "with-async-std")))]
+    fn driver_flush(&self, waker: &Waker) -> IoResult<bool> {
+        self.tasks.lock().unwrap().driver = Some(waker.clone());
+        self.socket.lock().unwrap().try_flush().map(|flushed| !flushed)
+    }
+
+    /// Dispatches the packets that can be read without blocking. Returns an error only if the
+    /// connection has been lost, as the packets that can't be decoded are skipped.
+    #[cfg(all(unix, any(feature = "with-tokio", feature = "with-async-std")))]
+    fn driver_read(&self) -> IoResult<()> {
+        match self.read_available() {
+            Ok(_) => Ok(()),
+            Err(_) if !self.reader.lock().unwrap().is_disconnected() => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// The reply is obtained without blocking, but requires the packets of the server to be read
+/// by a driver.
+///
+/// Completes with an error if the connection to the server has been lost, if the request
+/// couldn't be sent, or if the reply couldn't be decoded.
+impl<'a, T> Future for ReplyHandle<'a, T> {
+    type Output = IoResult<Result<T, XError>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<IoResult<Result<T, XError>>> {
+        self.connection.tasks.lock().unwrap().register(cx.waker());
+
+        let reply = {
+            let mut pending = self.connection.pending_answers.lock().unwrap();
+            pending.iter().position(|&(seq, _)| seq == self.sequence)
+                   .map(|index| pending.remove(index).1)
+        };
+
+        if let Some(reply) = reply {
+            return Poll::Ready((self.get_reply)(reply));
+        }
+
+        if self.connection.reader.lock().unwrap().is_disconnected() {
+            return Poll::Ready(Err(IoError::new(ErrorKind::NotConnected,
+                                                "The connection to the X server has been lost")));
+        }
+
+        // the server doesn't answer requests that are still in the output buffer
+        if let Err(err) = self.connection.try_flush() {
+            return Poll::Ready(Err(err));
+        }
+
+        Poll::Pending
+    }
+}
+
+/// Future that reads the packets sent by the server as soon as they arrive, using the reactor
+/// of tokio. Must be polled for the replies and events to be received.
+///
+/// Completes with an error once the connection to the server has been lost.
+#[cfg(all(unix, feature = "with-tokio"))]
+pub struct TokioDriver<'a> {
+    connection: &'a XConnection,
+    fd: ::tokio::io::unix::AsyncFd<RawFd>,
+}
+
+#[cfg(all(unix, feature = "with-tokio"))]
+impl<'a> Future for TokioDriver<'a> {
+    type Output = IoResult<()>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<IoResult<()>> {
+        loop {
+            // requests that the futures couldn't send without blocking
+            let pending = match self.connection.driver_flush(cx.waker()) {
+                Ok(pending) => pending,
+                Err(err) => return Poll::Ready(Err(err)),
+            };
+
+            if pending {
+                match self.fd.poll_write_ready(cx) {
+                    Poll::Ready(Ok(mut guard)) => {
+                        match self.connection.driver_flush(cx.waker()) {
+                            Ok(true) => guard.clear_ready(),
+                            Ok(false) => (),
+                            Err(err) => return Poll::Ready(Err(err)),
+                        }
+                        continue;
+                    },
+                    Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                    Poll::Pending => (),
+                }
+            }
+
+            let mut guard = match self.fd.poll_read_ready(cx) {
+                Poll::Ready(Ok(guard)) => guard,
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => return Poll::Pending,
+            };
+
+            // everything that can be read without blocking has been read
+            let result = self.connection.driver_read();
+            guard.clear_ready();
+
+            if let Err(err) = result {
+                return Poll::Ready(Err(err));
+            }
+        }
+    }
+}
+
+/// Future that reads the packets sent by the server as soon as they arrive, using the reactor
+/// of async-std. Must be polled for the replies and events to be received.
+///
+/// Completes with an error once the connection to the server has been lost.
+#[cfg(all(unix, feature = "with-async-std"))]
+pub struct AsyncStdDriver<'a> {
+    connection: &'a XConnection,
+    fd: ::async_io::Async<SocketFd>,
+}
+
+/// Gives the socket to the reactor without transferring its ownership.
+#[cfg(all(unix, feature = "with-async-std"))]
+struct SocketFd(RawFd);
+
+#[cfg(all(unix, feature = "with-async-std"))]
+impl ::std::os::unix::io::AsFd for SocketFd {
+    fn as_fd(&self) -> ::std::os::unix::io::BorrowedFd<'_> {
+        // the socket is owned by the connection, which outlives the driver
+        unsafe { ::std::os::unix::io::BorrowedFd::borrow_raw(self.0) }
+    }
+}
+
+#[cfg(all(unix, feature = "with-async-std"))]
+impl<'a> Future for AsyncStdDriver<'a> {
+    type Output = IoResult<()>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<IoResult<()>> {
+        loop {
+            // requests that the futures couldn't send without blocking
+            let pending = match self.connection.driver_flush(cx.waker()) {
+                Ok(pending) => pending,
+                Err(err) => return Poll::Ready(Err(err)),
+            };
+
+            if pending {
+                match self.fd.poll_writable(cx) {
+                    Poll::Ready(Ok(())) => continue,
+                    Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                    Poll::Pending => (),
+                }
+            }
+
+            match self.fd.poll_readable(cx) {
+                Poll::Ready(Ok(())) => (),
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => return Poll::Pending,
+            }
+
+            if let Err(err) = self.connection.driver_read() {
+                return Poll::Ready(Err(err));
+            }
+        }
+    }
+}
+
+impl XConnection {
+    /// Returns a future that reads the packets of the server with tokio.
+    ///
+    /// Must be called from within a tokio runtime.
+    #[cfg(all(unix, feature = "with-tokio"))]
+    pub fn tokio_driver(&self) -> IoResult<TokioDriver<'_>> {
+        let fd = self.driver_fd()?;
+        Ok(TokioDriver {
+            connection: self,
+            fd: ::tokio::io::unix::AsyncFd::new(fd)?,
+        })
+    }
+
+    /// Returns a future that reads the packets of the server with async-std.
+    #[cfg(all(unix, feature = "with-async-std"))]
+    pub fn async_std_driver(&self) -> IoResult<AsyncStdDriver<'_>> {
+        let fd = self.driver_fd()?;
+        Ok(AsyncStdDriver {
+            connection: self,
+            fd: ::async_io::Async::new_nonblocking(SocketFd(fd))?,
+        })
+    }
+}
@@ -0,0 +1,546 @@
+//! Reading of the Xauthority file, which contains the credentials used to connect to servers.
+
+use std::env;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::io::Result as IoResult;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use des;
+use stream::Stream;
+
+const XDM_AUTHORIZATION_1: &[u8] = b"XDM-AUTHORIZATION-1";
+const MIT_MAGIC_COOKIE_1: &[u8] = b"MIT-MAGIC-COOKIE-1";
+
+/// The authorization protocols that we support, by order of preference.
+const SUPPORTED_PROTOCOLS: &[&[u8]] = &[XDM_AUTHORIZATION_1, MIT_MAGIC_COOKIE_1];
+
+/// Number used in place of an address for XDM-AUTHORIZATION-1 over local sockets. Must be
+/// different for each connection.
+static XDM_LOCAL_ADDRESS: AtomicUsize = AtomicUsize::new(0);
+
+/// The kind of address of an entry in the Xauthority file.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AuthFamily {
+    /// An IPv4 address.
+    Internet,
+    /// An IPv6 address.
+    Internet6,
+    /// A local connection, whose address is the host name of the machine.
+    Local,
+    /// Matches any address.
+    Wild,
+    /// A family that we don't know.
+    Other(u16),
+}
+
+impl AuthFamily {
+    fn from_raw(raw: u16) -> AuthFamily {
+        match raw {
+            0 => AuthFamily::Internet,
+            6 => AuthFamily::Internet6,
+            256 => AuthFamily::Local,
+            65535 => AuthFamily::Wild,
+            other => AuthFamily::Other(other),
+        }
+    }
+}
+
+/// An entry of the Xauthority file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthEntry {
+    pub family: AuthFamily,
+    pub address: Vec<u8>,
+    /// The display number, as a string. Matches any display if empty.
+    pub number: String,
+    /// The name of the authorization protocol, for example `MIT-MAGIC-COOKIE-1`.
+    pub name: Vec<u8>,
+    pub data: Vec<u8>,
+}
+
+/// Returns the path of the Xauthority file, from `$XAUTHORITY` or `~/.Xauthority`.
+pub fn xauthority_path() -> Option<PathBuf> {
+    match env::var_os("XAUTHORITY") {
+        Some(ref path) if !path.is_empty() => return Some(PathBuf::from(path)),
+        _ => ()
+    };
+
+    match env::var_os("HOME") {
+        Some(ref home) if !home.is_empty() => Some(PathBuf::from(home).join(".Xauthority")),
+        _ => None
+    }
+}
+
+/// Reads all the entries of an Xauthority file.
+///
+/// If the file is truncated or can't be read, the entries that come before the error are
+/// returned.
+pub fn read_entries<R>(reader: R) -> Vec<AuthEntry> where R: Read {
+    let mut reader = BufReader::new(reader);
+    let mut entries = Vec::new();
+
+    while let Ok(Some(entry)) = read_entry(&mut reader) {
+        entries.push(entry);
+    }
+    entries
+}
+
+/// Reads the next entry of an Xauthority file. Returns `None` at the end of the file.
+fn read_entry<R>(reader: &mut R) -> IoResult<Option<AuthEntry>> where R: Read {
+    // the end of the file can only be at the start of an entry
+    let mut family = [0; 2];
+    if reader.read(&mut family[.. 1])? == 0 {
+        return Ok(None);
+    }
+    reader.read_exact(&mut family[1 ..])?;
+
+    let address = read_counted(reader)?;
+    let number = read_counted(reader)?;
+    let name = read_counted(reader)?;
+    let data = read_counted(reader)?;
+
+    Ok(Some(AuthEntry {
+        family: AuthFamily::from_raw(((family[0] as u16) << 8) | family[1] as u16),
+        address,
+        number: String::from_utf8_lossy(&number).into_owned(),
+        name,
+        data,
+    }))
+}
+
+/// Reads a 16-bit big-endian length followed by the corresponding number of bytes.
+fn read_counted<R>(reader: &mut R) -> IoResult<Vec<u8>> where R: Read {
+    let mut len = [0; 2];
+    reader.read_exact(&mut len)?;
+
+    let mut data = vec![0; ((len[0] as usize) << 8) | len[1] as usize];
+    reader.read_exact(&mut data)?;
+    Ok(data)
+}
+
+/// Returns the best entry for the given address and display among the ones whose
+/// protocol we support.
+pub fn find_entry<'a>(entries: &'a [AuthEntry], family: AuthFamily, address: &[u8],
+                      display: u16) -> Option<&'a AuthEntry>
+{
+    let display = display.to_string();
+
+    entries.iter()
+        .filter(|e| {
+            e.family == AuthFamily::Wild || (e.family == family && &e.address[..] == address)
+        })
+        .filter(|e| e.number.is_empty() || e.number == display)
+        .filter_map(|e| SUPPORTED_PROTOCOLS.iter().position(|p| &e.name[..] == *p).map(|p| (p, e)))
+        .min_by_key(|&(p, _)| p)
+        .map(|(_, e)| e)
+}
+
+/// Returns the family and the address under which the server of a connection is
+/// registered in the Xauthority file.
+pub fn peer_address(stream: &dyn Stream) -> (AuthFamily, Vec<u8>) {
+    match stream.peer_addr() {
+        Some(SocketAddr::V4(ref addr)) if !addr.ip().is_loopback() => {
+            (AuthFamily::Internet, addr.ip().octets().to_vec())
+        },
+        Some(SocketAddr::V6(ref addr)) if !addr.ip().is_loopback() => {
+            let segments = addr.ip().segments();
+
+            // IPv4-mapped addresses are registered as IPv4
+            if segments[.. 5] == [0; 5] && segments[5] == 0xffff {
+                let bytes = vec![(segments[6] >> 8) as u8, segments[6] as u8,
+                                 (segments[7] >> 8) as u8, segments[7] as u8];
+                if bytes[0] == 127 {
+                    (AuthFamily::Local, hostname())
+                } else {
+                    (AuthFamily::Internet, bytes)
+                }
+            } else {
+                let bytes = segments.iter().flat_map(|s| vec![(s >> 8) as u8, *s as u8]).collect();
+                (AuthFamily::Internet6, bytes)
+            }
+        },
+        // local connections, including TCP connections to the loopback interface
+        _ => (AuthFamily::Local, hostname())
+    }
+}
+
+/// Returns the authorization protocol name and data to send in the setup request.
+///
+/// Returns empty values if no entry of the Xauthority file matches.
+pub fn authorization(stream: &dyn Stream, display: u16) -> (Vec<u8>, Vec<u8>) {
+    match xauthority_path().and_then(|path| File::open(path).ok()) {
+        Some(file) => entries_authorization(read_entries(file), stream, display),
+        None => (Vec::new(), Vec::new())
+    }
+}
+
+/// Returns the authorization protocol name and data of the best entry for the server of a
+/// connection.
+fn entries_authorization(entries: Vec<AuthEntry>, stream: &dyn Stream, display: u16)
+                         -> (Vec<u8>, Vec<u8>)
+{
+    let (family, address) = peer_address(stream);
+
+    match find_entry(&entries, family, &address, display) {
+        Some(entry) if &entry.name[..] == XDM_AUTHORIZATION_1 => {
+            if let Some(data) = xdm_authorization(stream, &entry.data) {
+                return (entry.name.clone(), data);
+            }
+        },
+        Some(entry) => return (entry.name.clone(), entry.data.clone()),
+        None => return (Vec::new(), Vec::new())
+    }
+
+    // the entry of XDM-AUTHORIZATION-1 can't be used, but there may be one for
+    // MIT-MAGIC-COOKIE-1
+    let entries = entries.into_iter()
+                         .filter(|e| &e.name[..] != XDM_AUTHORIZATION_1)
+                         .collect::<Vec<_>>();
+
+    match find_entry(&entries, family, &address, display) {
+        Some(entry) => (entry.name.clone(), entry.data.clone()),
+        None => (Vec::new(), Vec::new())
+    }
+}
+
+/// Builds the data of XDM-AUTHORIZATION-1 from the 16 bytes of the Xauthority entry.
+///
+/// The first 8 bytes of the entry are sent back along with our address, port and the current
+/// time, the whole being encrypted with the 56-bit key stored in the last 7 bytes.
+///
+/// Returns `None` if the entry is invalid or if the connection is over IPv6, which the
+/// protocol doesn't support.
+fn xdm_authorization(stream: &dyn Stream, entry: &[u8]) -> Option<Vec<u8>> {
+    if entry.len() != 16 {
+        return None;
+    }
+
+    let mut address = Vec::with_capacity(6);
+    match stream.local_addr() {
+        Some(SocketAddr::V4(ref addr)) => {
+            address.extend(addr.ip().octets().iter().cloned());
+            address.push((addr.port() >> 8) as u8);
+            address.push(addr.port() as u8);
+        },
+        Some(SocketAddr::V6(ref addr)) => {
+            let segments = addr.ip().segments();
+            if segments[.. 5] != [0; 5] || segments[5] != 0xffff {
+                return None;
+            }
+
+            address.push((segments[6] >> 8) as u8);
+            address.push(segments[6] as u8);
+            address.push((segments[7] >> 8) as u8);
+            address.push(segments[7] as u8);
+            address.push((addr.port() >> 8) as u8);
+            address.push(addr.port() as u8);
+        },
+        None => {
+            // local connections use a counter and the process id instead
+            let counter = XDM_LOCAL_ADDRESS.fetch_add(1, Ordering::Relaxed) as u32;
+            let pid = process_id();
+            address.push((counter >> 24) as u8);
+            address.push((counter >> 16) as u8);
+            address.push((counter >> 8) as u8);
+            address.push(counter as u8);
+            address.push((pid >> 8) as u8);
+            address.push(pid as u8);
+        },
+    }
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    Some(xdm_authenticator(entry, &address, now as u32))
+}
+
+/// Encrypts the first 8 bytes of the Xauthority entry, followed by the 6 bytes of our address
+/// and port and by the time, with the key of the entry.
+fn xdm_authenticator(entry: &[u8], address: &[u8], now: u32) -> Vec<u8> {
+    let mut data = Vec::with_capacity(24);
+    data.extend(entry[.. 8].iter().cloned());
+    data.extend(address.iter().cloned());
+    data.push((now >> 24) as u8);
+    data.push((now >> 16) as u8);
+    data.push((now >> 8) as u8);
+    data.push(now as u8);
+
+    data.resize(24, 0);
+
+    // DES in CBC mode, with a null initialization vector
+    let key = expand_key(&entry[8 ..]);
+    let mut previous = [0; 8];
+    for chunk in data.chunks_mut(8) {
+        let mut block = [0; 8];
+        for (b, (&d, &p)) in block.iter_mut().zip(chunk.iter().zip(previous.iter())) {
+            *b = d ^ p;
+        }
+
+        previous = des::encrypt_block(&key, &block);
+        for (d, &p) in chunk.iter_mut().zip(previous.iter()) {
+            *d = p;
+        }
+    }
+
+    data
+}
+
+/// Spreads the 56 bits of the key stored in the last 7 bytes of `key` over 8 bytes, leaving
+/// the lowest bit of each byte for the parity.
+fn expand_key(key: &[u8]) -> [u8; 8] {
+    let bits = key[1 ..].iter().fold(0u64, |bits, &byte| (bits << 8) | byte as u64);
+
+    let mut expanded = [0; 8];
+    for (i, byte) in expanded.iter_mut().enumerate() {
+        let value = ((bits >> (49 - 7 * i)) & 0x7f) as u8;
+        // odd parity
+        let parity = 1 - (value.count_ones() & 1) as u8;
+        *byte = (value << 1) | parity;
+    }
+    expanded
+}
+
+#[cfg(unix)]
+fn process_id() -> u32 {
+    use libc;
+    unsafe { libc::getpid() as u32 }
+}
+
+#[cfg(not(unix))]
+fn process_id() -> u32 {
+    0
+}
+
+/// Returns the host name of the machine.
+#[cfg(unix)]
+fn hostname() -> Vec<u8> {
+    use libc;
+
+    let mut buf = [0u8; 256];
+    let result = unsafe {
+        libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len() as libc::size_t)
+    };
+
+    if result != 0 {
+        return Vec::new();
+    }
+
+    buf.iter().take_while(|&&b| b != 0).cloned().collect()
+}
+
+#[cfg(not(unix))]
+fn hostname() -> Vec<u8> {
+    env::var("COMPUTERNAME").map(|n| n.into_bytes()).unwrap_or(Vec::new())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Result as IoResult, Write};
+    use std::net::SocketAddr;
+
+    use stream::Stream;
+    use super::{entries_authorization, expand_key, find_entry, read_entries, xdm_authenticator};
+    use super::{AuthEntry, AuthFamily};
+
+    /// Encodes an entry the way it is stored in the Xauthority file.
+    fn encode(family: u16, address: &[u8], number: &str, name: &str, data: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![(family >> 8) as u8, family as u8];
+        for field in &[address, number.as_bytes(), name.as_bytes(), data] {
+            bytes.push((field.len() >> 8) as u8);
+            bytes.push(field.len() as u8);
+            bytes.extend_from_slice(field);
+        }
+        bytes
+    }
+
+    fn entry(family: AuthFamily, address: &[u8], number: &str, name: &str) -> AuthEntry {
+        AuthEntry {
+            family,
+            address: address.to_vec(),
+            number: number.to_string(),
+            name: name.as_bytes().to_vec(),
+            data: vec![1, 2, 3],
+        }
+    }
+
+    #[test]
+    fn read() {
+        let mut file = encode(256, b"box", "0", "MIT-MAGIC-COOKIE-1", &[1, 2, 3]);
+        file.extend(encode(0, &[10, 0, 0, 1], "", "XDM-AUTHORIZATION-1", &[1, 2, 3]));
+        file.extend(encode(42, b"", "12", "OTHER", &[1, 2, 3]));
+
+        assert_eq!(read_entries(&file[..]), vec![
+            entry(AuthFamily::Local, b"box", "0", "MIT-MAGIC-COOKIE-1"),
+            entry(AuthFamily::Internet, &[10, 0, 0, 1], "", "XDM-AUTHORIZATION-1"),
+            entry(AuthFamily::Other(42), b"", "12", "OTHER"),
+        ]);
+
+        assert_eq!(read_entries(&[][..]), vec![]);
+    }
+
+    #[test]
+    fn read_truncated() {
+        let mut file = encode(256, b"box", "0", "MIT-MAGIC-COOKIE-1", &[1, 2, 3]);
+        let second = encode(256, b"box", "1", "MIT-MAGIC-COOKIE-1", &[1, 2, 3]);
+        file.extend_from_slice(&second[.. second.len() - 1]);
+
+        // the entries before the error are kept
+        assert_eq!(read_entries(&file[..]),
+                   vec![entry(AuthFamily::Local, b"box", "0", "MIT-MAGIC-COOKIE-1")]);
+    }
+
+    #[test]
+    fn family_and_address() {
+        let entries = vec![
+            entry(AuthFamily::Internet, &[10, 0, 0, 1], "0", "MIT-MAGIC-COOKIE-1"),
+            entry(AuthFamily::Internet6, &[0; 16], "0", "MIT-MAGIC-COOKIE-1"),
+        ];
+
+        let found = find_entry(&entries, AuthFamily::Internet, &[10, 0, 0, 1], 0);
+        assert_eq!(found, Some(&entries[0]));
+        let found = find_entry(&entries, AuthFamily::Internet6, &[0; 16], 0);
+        assert_eq!(found, Some(&entries[1]));
+
+        assert_eq!(find_entry(&entries, AuthFamily::Internet, &[10, 0, 0, 2], 0), None);
+        // the same bytes in another family don't match
+        assert_eq!(find_entry(&entries, AuthFamily::Local, &[10, 0, 0, 1], 0), None);
+    }
+
+    #[test]
+    fn local_hostname() {
+        let entries = vec![
+            entry(AuthFamily::Local, b"other", "0", "MIT-MAGIC-COOKIE-1"),
+            entry(AuthFamily::Local, b"box", "0", "MIT-MAGIC-COOKIE-1"),
+        ];
+
+        // local entries are registered under the host name of the machine
+        assert_eq!(find_entry(&entries, AuthFamily::Local, b"box", 0), Some(&entries[1]));
+        assert_eq!(find_entry(&entries, AuthFamily::Local, b"elsewhere", 0), None);
+        assert_eq!(find_entry(&entries, AuthFamily::Internet, b"box", 0), None);
+    }
+
+    #[test]
+    fn display_number() {
+        let entries = vec![
+            entry(AuthFamily::Local, b"box", "1", "MIT-MAGIC-COOKIE-1"),
+            entry(AuthFamily::Local, b"box", "10", "MIT-MAGIC-COOKIE-1"),
+        ];
+
+        assert_eq!(find_entry(&entries, AuthFamily::Local, b"box", 1), Some(&entries[0]));
+        assert_eq!(find_entry(&entries, AuthFamily::Local, b"box", 10), Some(&entries[1]));
+        assert_eq!(find_entry(&entries, AuthFamily::Local, b"box", 0), None);
+    }
+
+    #[test]
+    fn wildcards() {
+        let entries = vec![
+            entry(AuthFamily::Local, b"box", "", "MIT-MAGIC-COOKIE-1"),
+            entry(AuthFamily::Wild, b"", "7", "MIT-MAGIC-COOKIE-1"),
+        ];
+
+        // an empty display number matches any display
+        assert_eq!(find_entry(&entries, AuthFamily::Local, b"box", 3), Some(&entries[0]));
+        // the wild family matches any address
+        assert_eq!(find_entry(&entries, AuthFamily::Internet, &[10, 0, 0, 1], 7),
+                   Some(&entries[1]));
+        assert_eq!(find_entry(&entries, AuthFamily::Internet, &[10, 0, 0, 1], 3), None);
+    }
+
+    #[test]
+    fn protocols() {
+        let entries = vec![
+            entry(AuthFamily::Local, b"box", "0", "UNKNOWN-PROTOCOL"),
+            entry(AuthFamily::Local, b"box", "0", "MIT-MAGIC-COOKIE-1"),
+            entry(AuthFamily::Local, b"box", "0", "XDM-AUTHORIZATION-1"),
+        ];
+
+        // unsupported protocols are ignored, and XDM-AUTHORIZATION-1 is preferred
+        assert_eq!(find_entry(&entries, AuthFamily::Local, b"box", 0), Some(&entries[2]));
+        assert_eq!(find_entry(&entries[.. 2], AuthFamily::Local, b"box", 0), Some(&entries[1]));
+        assert_eq!(find_entry(&entries[.. 1], AuthFamily::Local, b"box", 0), None);
+    }
+
+    /// A TCP connection that only has addresses.
+    struct Connection {
+        peer: SocketAddr,
+        local: SocketAddr,
+    }
+
+    impl Read for Connection {
+        fn read(&mut self, _: &mut [u8]) -> IoResult<usize> {
+            Ok(0)
+        }
+    }
+
+    impl Write for Connection {
+        fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> IoResult<()> {
+            Ok(())
+        }
+    }
+
+    impl Stream for Connection {
+        fn peer_addr(&self) -> Option<SocketAddr> {
+            Some(self.peer)
+        }
+
+        fn local_addr(&self) -> Option<SocketAddr> {
+            Some(self.local)
+        }
+    }
+
+    #[test]
+    fn key_expansion() {
+        // the first byte isn't part of the key
+        let key = [0xaa, 0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc, 0xde];
+        assert_eq!(expand_key(&key), [0x13, 0x1a, 0x15, 0xce, 0x89, 0xd5, 0xf2, 0xbc]);
+
+        assert_eq!(expand_key(&[0; 8]), [0x01; 8]);
+        assert_eq!(expand_key(&[0xff; 8]), [0xfe; 8]);
+    }
+
+    #[test]
+    fn xdm() {
+        let entry = [1, 2, 3, 4, 5, 6, 7, 8, 0xaa, 0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc, 0xde];
+        let address = [192, 168, 1, 2, 0x1f, 0x90];
+        assert_eq!(xdm_authenticator(&entry, &address, 0x5f5e1000), vec![
+            0x75, 0x2c, 0xfe, 0xd6, 0xe5, 0x50, 0x75, 0x3e,
+            0xb0, 0x55, 0x3e, 0x6d, 0x0d, 0x7c, 0xb7, 0x07,
+            0xed, 0x41, 0xb1, 0xdb, 0xc5, 0x2d, 0xe2, 0x91,
+        ]);
+    }
+
+    #[test]
+    fn xdm_fallback() {
+        let mut xdm = entry(AuthFamily::Wild, b"", "0", "XDM-AUTHORIZATION-1");
+        xdm.data = vec![0; 16];
+        let mit = entry(AuthFamily::Wild, b"", "0", "MIT-MAGIC-COOKIE-1");
+
+        let ipv4 = Connection {
+            peer: "10.0.0.1:6000".parse().unwrap(),
+            local: "10.0.0.2:40000".parse().unwrap(),
+        };
+        let ipv6 = Connection {
+            peer: "[2001:db8::1]:6000".parse().unwrap(),
+            local: "[2001:db8::2]:40000".parse().unwrap(),
+        };
+
+        let (name, data) = entries_authorization(vec![xdm.clone(), mit.clone()], &ipv4, 0);
+        assert_eq!((&name[..], data.len()), (&b"XDM-AUTHORIZATION-1"[..], 24));
+
+        // XDM-AUTHORIZATION-1 doesn't support IPv6
+        let authorization = entries_authorization(vec![xdm.clone(), mit.clone()], &ipv6, 0);
+        assert_eq!(authorization, (mit.name.clone(), mit.data.clone()));
+        let authorization = entries_authorization(vec![xdm.clone()], &ipv6, 0);
+        assert_eq!(authorization, (Vec::new(), Vec::new()));
+
+        // the data of XDM-AUTHORIZATION-1 must be 16 bytes long
+        xdm.data = vec![0; 15];
+        let authorization = entries_authorization(vec![xdm, mit.clone()], &ipv4, 0);
+        assert_eq!(authorization, (mit.name.clone(), mit.data.clone()));
+    }
+}
@@ -0,0 +1,273 @@
+//! Parsing of display names, as found in the `DISPLAY` environment variable.
+
+use std::env;
+use std::net::TcpStream;
+use std::path::PathBuf;
+
+use stream;
+use {XConnection, ConnectError, DisplayStream};
+
+/// The port of the first display when using TCP.
+const X_TCP_PORT: u16 = 6000;
+
+/// The result of parsing a display name such as `:0`, `host:1.0` or `tcp/host:0`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DisplayName {
+    /// The protocol before the `/`, if any (`tcp`, `inet`, `inet6`, `unix`...).
+    pub protocol: Option<String>,
+
+    /// The host name. Empty for a local connection.
+    pub host: String,
+
+    /// The display number.
+    pub display: u16,
+
+    /// The screen number, or 0 if not specified.
+    pub screen: usize,
+
+    /// The path of the socket, for names that start with `/`.
+    pub path: Option<PathBuf>,
+}
+
+/// The way to reach the X server described by a `DisplayName`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Transport {
+    /// A TCP connection to a host and a port.
+    Tcp(String, u16),
+
+    /// A local connection through a socket.
+    Local(PathBuf),
+}
+
+impl DisplayName {
+    /// Parses a display name. Returns `None` if the name is invalid.
+    pub fn parse(name: &str) -> Option<DisplayName> {
+        // a path to a socket, optionally followed by `:display.screen` (as used by launchd)
+        if name.starts_with('/') {
+            let last_slash = name.rfind('/').unwrap();
+
+            return match name[last_slash ..].rfind(':') {
+                Some(colon) => {
+                    let colon = last_slash + colon;
+                    let (display, screen) = parse_display_screen(&name[colon + 1 ..])?;
+
+                    Some(DisplayName {
+                        protocol: None,
+                        host: String::new(),
+                        display,
+                        screen,
+                        // the `:display` suffix is part of the name of the socket
+                        path: Some(PathBuf::from(name)),
+                    })
+                },
+                None => {
+                    // `/tmp/.X11-unix/X0`
+                    let file = &name[last_slash + 1 ..];
+                    let display = file.strip_prefix('X')?.parse().ok()?;
+
+                    Some(DisplayName {
+                        protocol: None,
+                        host: String::new(),
+                        display,
+                        screen: 0,
+                        path: Some(PathBuf::from(name)),
+                    })
+                },
+            };
+        }
+
+        let (protocol, rest) = match name.find('/') {
+            Some(slash) => (Some(name[.. slash].to_string()), &name[slash + 1 ..]),
+            None => (None, name)
+        };
+
+        let colon = rest.rfind(':')?;
+
+        let host = &rest[.. colon];
+
+        // `host::0` is a DECnet address, which we don't support
+        if host.ends_with(':') && !host.starts_with('[') && host.matches(':').count() == 1 {
+            return None;
+        }
+
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+
+        let (display, screen) = parse_display_screen(&rest[colon + 1 ..])?;
+
+        Some(DisplayName {
+            protocol,
+            host: host.to_string(),
+            display,
+            screen,
+            path: None,
+        })
+    }
+
+    /// Returns the way to reach the server.
+    ///
+    /// Returns `None` if the display number is too large for the TCP port of the display.
+    pub fn transport(&self) -> Option<Transport> {
+        if let Some(ref path) = self.path {
+            return Some(Transport::Local(path.clone()));
+        }
+
+        let local = match self.protocol.as_ref().map(|p| &p[..]) {
+            Some("unix") => true,
+            Some(_) => false,
+            None => self.host.is_empty() || self.host == "unix",
+        };
+
+        if local {
+            Some(Transport::Local(PathBuf::from(format!("/tmp/.X11-unix/X{}", self.display))))
+        } else {
+            let host = if self.host.is_empty() { "localhost" } else { &self.host[..] };
+            Some(Transport::Tcp(host.to_string(), tcp_port(self.display)?))
+        }
+    }
+}
+
+/// Returns the TCP port of a display, or `None` if it is out of range.
+fn tcp_port(display: u16) -> Option<u16> {
+    X_TCP_PORT.checked_add(display)
+}
+
+/// Parses the `display[.screen]` part of a display name.
+fn parse_display_screen(text: &str) -> Option<(u16, usize)> {
+    let mut parts = text.splitn(2, '.');
+
+    let display = parts.next()?.parse().ok()?;
+
+    let screen = match parts.next() {
+        Some(s) => match s.parse() { Ok(s) => s, Err(_) => return None },
+        None => 0
+    };
+
+    Some((display, screen))
+}
+
+impl XConnection<DisplayStream> {
+    /// Connects to the X server given by the `DISPLAY` environment variable.
+    pub fn connect_default() -> Result<XConnection<DisplayStream>, ConnectError> {
+        match env::var("DISPLAY") {
+            Ok(ref name) if !name.is_empty() => XConnection::connect_display(name),
+            _ => Err(ConnectError::DisplayNotSet),
+        }
+    }
+
+    /// Connects to the X server described by a display name, such as `:0` or `host:1.0`.
+    ///
+    /// The default screen of the connection is the one given by the display name.
+    pub fn connect_display(name: &str) -> Result<XConnection<DisplayStream>, ConnectError> {
+        let invalid = || ConnectError::InvalidDisplay(name.to_string());
+
+        let display = DisplayName::parse(name).ok_or_else(invalid)?;
+
+        let socket = match display.transport().ok_or_else(invalid)? {
+            Transport::Tcp(host, port) => {
+                DisplayStream::Tcp(TcpStream::connect((&host[..], port))?)
+            },
+            #[cfg(unix)]
+            Transport::Local(path) => DisplayStream::Unix(stream::connect_unix(&path)?),
+            // there are no local sockets, so we use the TCP port of the display
+            #[cfg(not(unix))]
+            Transport::Local(_) => {
+                let port = tcp_port(display.display).ok_or_else(invalid)?;
+                DisplayStream::Tcp(TcpStream::connect(("localhost", port))?)
+            },
+        };
+
+        XConnection::setup_connection(socket, display.display, display.screen)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::{DisplayName, Transport};
+
+    fn name(protocol: Option<&str>, host: &str, display: u16, screen: usize,
+            path: Option<&str>) -> DisplayName
+    {
+        DisplayName {
+            protocol: protocol.map(|p| p.to_string()),
+            host: host.to_string(),
+            display,
+            screen,
+            path: path.map(PathBuf::from),
+        }
+    }
+
+    #[test]
+    fn host_display_screen() {
+        assert_eq!(DisplayName::parse(":0"), Some(name(None, "", 0, 0, None)));
+        assert_eq!(DisplayName::parse("host:1.2"), Some(name(None, "host", 1, 2, None)));
+        assert_eq!(DisplayName::parse("10.0.0.1:12"), Some(name(None, "10.0.0.1", 12, 0, None)));
+
+        assert_eq!(DisplayName::parse("host"), None);
+        assert_eq!(DisplayName::parse("host:"), None);
+        assert_eq!(DisplayName::parse("host:x"), None);
+        assert_eq!(DisplayName::parse("host:0."), None);
+        assert_eq!(DisplayName::parse("host::0"), None);
+    }
+
+    #[test]
+    fn unix() {
+        let display = DisplayName::parse("unix:3").unwrap();
+        assert_eq!(display, name(None, "unix", 3, 0, None));
+        assert_eq!(display.transport(), Some(Transport::Local(PathBuf::from("/tmp/.X11-unix/X3"))));
+
+        let display = DisplayName::parse("unix/:1").unwrap();
+        assert_eq!(display, name(Some("unix"), "", 1, 0, None));
+        assert_eq!(display.transport(), Some(Transport::Local(PathBuf::from("/tmp/.X11-unix/X1"))));
+    }
+
+    #[test]
+    fn tcp() {
+        let display = DisplayName::parse("tcp/host:1.1").unwrap();
+        assert_eq!(display, name(Some("tcp"), "host", 1, 1, None));
+        assert_eq!(display.transport(), Some(Transport::Tcp("host".to_string(), 6001)));
+
+        // without a host, TCP connects to the local machine
+        let display = DisplayName::parse("tcp/:0").unwrap();
+        assert_eq!(display.transport(), Some(Transport::Tcp("localhost".to_string(), 6000)));
+
+        // the port would overflow
+        let display = DisplayName::parse("tcp/host:60000").unwrap();
+        assert_eq!(display.transport(), None);
+        let display = DisplayName::parse(":60000").unwrap();
+        assert_eq!(display.transport(),
+                   Some(Transport::Local(PathBuf::from("/tmp/.X11-unix/X60000"))));
+    }
+
+    #[test]
+    fn ipv6() {
+        assert_eq!(DisplayName::parse("[::1]:0"), Some(name(None, "::1", 0, 0, None)));
+        assert_eq!(DisplayName::parse("inet6/[fe80::1]:2.1"),
+                   Some(name(Some("inet6"), "fe80::1", 2, 1, None)));
+        assert_eq!(DisplayName::parse("::1:0"), Some(name(None, "::1", 0, 0, None)));
+    }
+
+    #[test]
+    fn path() {
+        let display = DisplayName::parse("/tmp/.X11-unix/X5").unwrap();
+        assert_eq!(display, name(None, "", 5, 0, Some("/tmp/.X11-unix/X5")));
+        assert_eq!(display.transport(), Some(Transport::Local(PathBuf::from("/tmp/.X11-unix/X5"))));
+
+        assert_eq!(DisplayName::parse("/tmp/.X11-unix/Xfoo"), None);
+        assert_eq!(DisplayName::parse("/tmp/.X11-unix/socket"), None);
+    }
+
+    #[test]
+    fn launchd() {
+        let path = "/private/tmp/com.apple.launchd.AbCd/org.xquartz:0";
+        let display = DisplayName::parse(path).unwrap();
+        assert_eq!(display, name(None, "", 0, 0, Some(path)));
+        assert_eq!(display.transport(), Some(Transport::Local(PathBuf::from(path))));
+
+        let display = DisplayName::parse("/private/tmp/launchd-1.x/org.x:org.x11:1.2").unwrap();
+        assert_eq!((display.display, display.screen), (1, 2));
+
+        assert_eq!(DisplayName::parse("/private/tmp/launchd/org.xquartz:x"), None);
+    }
+}
@@ -0,0 +1,99 @@
+//! Retrieval of the events received from the server.
+
+use std::io::Result as IoResult;
+use std::time::{Duration, Instant};
+
+use {Event, XConnection};
+
+/// Iterator for the events received by the server.
+///
+/// Each call to `next` blocks until an event is received, and returns the errors that happen
+/// while receiving. The iteration stops after the error caused by the loss of the connection
+/// to the server.
+pub struct Events<'a> {
+    connection: &'a XConnection,
+}
+
+impl<'a> Iterator for Events<'a> {
+    type Item = IoResult<Event>;
+
+    fn next(&mut self) -> Option<IoResult<Event>> {
+        // the events received before the connection was lost are still returned
+        if let Some(event) = self.connection.poll_for_queued_event() {
+            return Some(Ok(event));
+        }
+
+        if self.connection.reader.lock().unwrap().is_disconnected() {
+            return None;
+        }
+
+        Some(self.connection.wait_for_event())
+    }
+}
+
+/// The events are obtained without blocking, but require the packets of the server to be read
+/// by a driver.
+#[cfg(any(feature = "with-tokio", feature = "with-async-std"))]
+impl<'a> ::futures_core::Stream for Events<'a> {
+    type Item = Event;
+
+    fn poll_next(self: ::std::pin::Pin<&mut Self>, cx: &mut ::std::task::Context)
+                 -> ::std::task::Poll<Option<Event>>
+    {
+        self.connection.poll_next_event(cx)
+    }
+}
+
+impl XConnection {
+    /// Returns an iterator that waits for the events sent by the server.
+    pub fn events(&self) -> Events<'_> {
+        Events {
+            connection: self,
+        }
+    }
+
+    /// Returns the next event, blocking until one is received.
+    pub fn wait_for_event(&self) -> IoResult<Event> {
+        let mut event = None;
+        self.process_until(|| {
+            event = self.poll_for_queued_event();
+            event.is_some()
+        })?;
+        Ok(event.unwrap())
+    }
+
+    /// Returns the next event if one has already been received or can be read without
+    /// blocking.
+    pub fn poll_for_event(&self) -> IoResult<Option<Event>> {
+        if let Some(event) = self.poll_for_queued_event() {
+            return Ok(Some(event));
+        }
+
+        self.read_available()?;
+        Ok(self.poll_for_queued_event())
+    }
+
+    /// Returns the next event that has already been received, without reading from the
+    /// socket.
+    pub fn poll_for_queued_event(&self) -> Option<Event> {
+        self.pending_events.lock().unwrap().pop_front()
+    }
+
+    /// Returns the next event, or `None` if none has been received after `timeout`.
+    pub fn wait_for_event_timeout(&self, timeout: Duration) -> IoResult<Option<Event>> {
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            if let Some(event) = self.poll_for_queued_event() {
+                return Ok(Some(event));
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+
+            self.process_next_timeout(deadline - now)?;
+        }
+    }
+}
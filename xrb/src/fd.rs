@@ -0,0 +1,47 @@
+//! Access to the file descriptor of the connection, for the event loops that wait on it.
+//!
+//! Once the descriptor is readable, `read_available` dispatches the packets that have been
+//! received without blocking. Requests may stay in the output buffer until `flush` is called.
+
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, RawFd};
+
+use {Stream, XConnection};
+
+impl<S> AsRawFd for XConnection<S> where S: Stream + AsRawFd + 'static {
+    /// Returns the file descriptor of the socket, which is closed when the connection is
+    /// destroyed.
+    fn as_raw_fd(&self) -> RawFd {
+        match self.raw_fd {
+            Some(fd) => fd,
+            None => self.socket.lock().unwrap().stream.as_raw_fd(),
+        }
+    }
+}
+
+impl<S> AsFd for XConnection<S> where S: Stream + AsRawFd + 'static {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        // the socket is closed when the connection is destroyed
+        unsafe { BorrowedFd::borrow_raw(self.as_raw_fd()) }
+    }
+}
+
+/// Registers the socket of the connection. Only the readiness of the socket is reported,
+/// so the packets must then be read with `read_available`.
+#[cfg(feature = "with-mio")]
+impl<S> ::mio::event::Source for XConnection<S> where S: Stream + AsRawFd + 'static {
+    fn register(&mut self, registry: &::mio::Registry, token: ::mio::Token,
+                interests: ::mio::Interest) -> ::std::io::Result<()>
+    {
+        ::mio::unix::SourceFd(&self.as_raw_fd()).register(registry, token, interests)
+    }
+
+    fn reregister(&mut self, registry: &::mio::Registry, token: ::mio::Token,
+                  interests: ::mio::Interest) -> ::std::io::Result<()>
+    {
+        ::mio::unix::SourceFd(&self.as_raw_fd()).reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &::mio::Registry) -> ::std::io::Result<()> {
+        ::mio::unix::SourceFd(&self.as_raw_fd()).deregister(registry)
+    }
+}
@@ -1,3 +1,164 @@
-#![allow(missing_doc)]
+#![allow(missing_docs)]
+
+extern crate libc;
+
+#[cfg(feature = "with-tokio")]
+extern crate tokio;
+#[cfg(feature = "with-async-std")]
+extern crate async_io;
+#[cfg(any(feature = "with-tokio", feature = "with-async-std"))]
+extern crate futures_core;
+#[cfg(all(unix, feature = "with-mio"))]
+extern crate mio;
+
+#[cfg(all(unix, feature = "with-async-std"))]
+pub use asynchronous::AsyncStdDriver;
+#[cfg(all(unix, feature = "with-tokio"))]
+pub use asynchronous::TokioDriver;
+pub use auth::{AuthEntry, AuthFamily};
+pub use display::{DisplayName, Transport};
+pub use events::Events;
+
+pub use stream::{DisplayStream, Stream};
+use asynchronous::WaitingTasks;
+use receive::ReaderState;
+use sequence::SequenceTracker;
+use stream::BufferedStream;
+use xid::XidAllocator;
+
+mod asynchronous;
+mod auth;
+mod des;
+mod display;
+mod events;
+#[cfg(unix)]
+mod fd;
+mod receive;
+mod sequence;
+mod stream;
+mod xid;
 
 include!(concat!(env!("OUT_DIR"), "/output.rs"));
+
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind;
+
+    use stream::tests::MemoryStream;
+    use {ByteOrder, ConnectError, PropMode, XConnection, ATOM, WINDOW};
+
+    /// Returns the answer of the server to the setup request, without any screen.
+    fn setup_reply(maximum_request_length: u16) -> Vec<u8> {
+        let mut reply = vec![1, 0, 11, 0, 0, 0, 8, 0];
+        // release number, base and mask of the resource IDs and size of the motion buffer
+        reply.extend_from_slice(&[0; 4]);
+        reply.extend_from_slice(&0x0020_0000u32.to_le_bytes());
+        reply.extend_from_slice(&0x001f_ffffu32.to_le_bytes());
+        reply.extend_from_slice(&[0; 4]);
+        // length of the vendor, followed by the maximum length of the requests
+        reply.extend_from_slice(&[0; 2]);
+        reply.extend_from_slice(&maximum_request_length.to_le_bytes());
+        reply.extend_from_slice(&[0, 0, 0, 0, 32, 32, 8, 255, 0, 0, 0, 0]);
+        reply
+    }
+
+    /// Performs the handshake over an in-memory stream, with the little endian byte order.
+    pub fn connect(server: &MemoryStream, maximum_request_length: u16)
+                   -> XConnection<MemoryStream>
+    {
+        server.send(&setup_reply(maximum_request_length));
+        let connection = XConnection::from_stream(server.clone(), ByteOrder::LittleEndian,
+                                                  b"", b"").unwrap();
+        server.take_written();
+        connection
+    }
+
+    /// Pads the beginning of a packet of the server to 32 bytes.
+    pub fn packet(bytes: &[u8]) -> Vec<u8> {
+        let mut packet = bytes.to_vec();
+        packet.resize(32, 0);
+        packet
+    }
+
+    #[test]
+    fn handshake() {
+        let server = MemoryStream::default();
+        server.send(&setup_reply(0xffff));
+
+        let connection = XConnection::from_stream(server.clone(), ByteOrder::LittleEndian,
+                                                  b"MIT-MAGIC-COOKIE-1", &[7; 16]).unwrap();
+        assert_eq!(connection.setup().resource_id_base, 0x0020_0000);
+        assert_eq!(connection.setup().maximum_request_length, 0xffff);
+
+        let mut expected = vec![b'l', 0, 11, 0, 0, 0, 18, 0, 16, 0, 0, 0];
+        expected.extend_from_slice(b"MIT-MAGIC-COOKIE-1\0\0");
+        expected.extend_from_slice(&[7; 16]);
+        assert_eq!(server.take_written(), expected);
+    }
+
+    #[test]
+    fn handshake_with_long_authorization() {
+        let server = MemoryStream::default();
+        server.send(&setup_reply(0xffff));
+
+        let result = XConnection::from_stream(server.clone(), ByteOrder::LittleEndian,
+                                              b"MIT-MAGIC-COOKIE-1", &vec![0; 0x10000]);
+        match result {
+            Err(ConnectError::IoError(ref err)) if err.kind() == ErrorKind::InvalidInput => (),
+            _ => panic!("The authorization data should be refused"),
+        }
+        assert_eq!(server.take_written(), vec![]);
+    }
+
+    #[test]
+    fn framed_request() {
+        let server = MemoryStream::default();
+        let connection = connect(&server, 0xffff);
+
+        let handle = connection.InternAtom_request(false, b"WM_NAME").unwrap();
+        connection.flush().unwrap();
+        assert_eq!(server.take_written(), b"\x10\x00\x04\x00\x07\x00\x00\x00WM_NAME\x00");
+
+        server.send(&packet(&[1, 0, 1, 0, 0, 0, 0, 0, 39, 0, 0, 0]));
+        assert_eq!(handle.get().unwrap().atom, ATOM(39));
+
+        // the length of the name doesn't fit in 16 bits
+        let err = connection.InternAtom_request(false, &vec![b'a'; 0x10000]).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        connection.flush().unwrap();
+        assert_eq!(server.take_written(), vec![]);
+    }
+
+    #[test]
+    fn big_request() {
+        let server = MemoryStream::default();
+        let connection = connect(&server, 0xffff);
+
+        // the replies to QueryExtension and BigRequestsEnable
+        server.send(&packet(&[1, 0, 1, 0, 0, 0, 0, 0, 1, 133, 0, 0]));
+        server.send(&packet(&[1, 0, 2, 0, 0, 0, 0, 0, 0, 0, 0x40, 0]));
+
+        let data = vec![7; 300_000];
+        connection.ChangeProperty_request(PropMode::Replace, WINDOW(5), ATOM(39), ATOM(31), 8,
+                                          data.len() as u32, &data).unwrap();
+        connection.flush().unwrap();
+        assert_eq!(connection.maximum_request_length(), 0x40_0000 * 4);
+
+        let written = server.take_written();
+        assert_eq!(&written[.. 20], b"\x62\x00\x05\x00\x0c\x00\x00\x00BIG-REQUESTS");
+        assert_eq!(&written[20 .. 24], &[133, 0, 1, 0]);
+
+        // the 16 bits length is 0, and is followed by the length including these 4 bytes
+        let request = &written[24 ..];
+        assert_eq!(request.len(), 24 + 300_000 + 4);
+        assert_eq!(&request[.. 8], &[18, 0, 0, 0, 0xff, 0x24, 0x01, 0x00]);
+        assert_eq!(&request[8 .. 28], &[5, 0, 0, 0, 39, 0, 0, 0, 31, 0, 0, 0, 8, 0, 0, 0,
+                                        0xe0, 0x93, 0x04, 0x00]);
+        assert!(request[28 ..].iter().all(|&byte| byte == 7));
+
+        // the requests that fit keep a 16 bits length
+        connection.UnmapWindow_request(WINDOW(5)).unwrap();
+        connection.flush().unwrap();
+        assert_eq!(server.take_written(), vec![10, 0, 2, 0, 5, 0, 0, 0]);
+    }
+}
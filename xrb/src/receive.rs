@@ -0,0 +1,509 @@
+//! Reception of the packets sent by the server, and their dispatching to the replies and
+//! events that are waiting for them.
+
+use std::io::{Error as IoError, ErrorKind, Read};
+use std::io::Cursor as IoCursor;
+use std::io::Result as IoResult;
+use std::mem;
+use std::time::{Duration, Instant};
+
+use {request_name, ByteOrder, Event, Reply, SocketRecv, UnknownError, XConnection, XError};
+use stream::Stream;
+
+#[cfg(unix)]
+use libc;
+#[cfg(unix)]
+use stream::poll_fd;
+
+/// Code of the generic events, whose length is given in their header like for replies.
+const GENERIC_EVENT: u8 = 35;
+
+/// Code of the only event that doesn't contain a sequence number.
+const KEYMAP_NOTIFY: u8 = 11;
+
+/// How long reading from the socket can wait for the server to send something.
+#[derive(Debug, Copy, Clone)]
+enum Wait {
+    /// Only what has already been received is read.
+    Never,
+    Until(Instant),
+    Forever,
+}
+
+/// Lets a single thread read from the socket at a time, which keeps the packets in order, while
+/// the others wait for it to dispatch something.
+pub struct ReaderState {
+    /// True while a thread is reading.
+    reading: bool,
+    /// Number of packets that have been dispatched.
+    received: usize,
+    /// True once reading from the socket has failed, after which nothing will be received.
+    disconnected: bool,
+}
+
+impl ReaderState {
+    pub fn new() -> ReaderState {
+        ReaderState {
+            reading: false,
+            received: 0,
+            disconnected: false,
+        }
+    }
+
+    /// Returns true if the connection to the server has been lost.
+    pub fn is_disconnected(&self) -> bool {
+        self.disconnected
+    }
+}
+
+impl XConnection {
+    /// Reads the next packet sent by the server and dispatches it, along with the ones that
+    /// have been received at the same time. Blocks until a whole packet has been received.
+    ///
+    /// The requests in the output buffer are sent first, as the server may not send anything
+    /// until it receives them.
+    ///
+    /// If another thread is already reading, this waits for it to dispatch a packet instead.
+    pub fn process_next(&self) -> IoResult<()> {
+        let received = self.reader.lock().unwrap().received;
+        self.flush()?;
+        self.receive(received, Wait::Forever).map(|_| ())
+    }
+
+    /// Reads and dispatches the packets sent by the server until `ready` returns true.
+    ///
+    /// `ready` is called before reading each packet, including after other threads have
+    /// dispatched packets, so this never blocks once what it checks for has been received.
+    pub fn process_until<F>(&self, mut ready: F) -> IoResult<()> where F: FnMut() -> bool {
+        loop {
+            // read before calling `ready`, in case another thread dispatches what it checks for
+            // in between
+            let received = self.reader.lock().unwrap().received;
+            if ready() {
+                return Ok(());
+            }
+
+            self.flush()?;
+            self.receive(received, Wait::Forever)?;
+        }
+    }
+
+    /// Same as `process_next`, but gives up if no packet has been received after `timeout`.
+    ///
+    /// Returns true if a packet has been dispatched.
+    pub fn process_next_timeout(&self, timeout: Duration) -> IoResult<bool> {
+        let deadline = Instant::now() + timeout;
+        let received = self.reader.lock().unwrap().received;
+        self.flush()?;
+        self.receive(received, Wait::Until(deadline))
+    }
+
+    /// Dispatches all the packets that can be read without blocking.
+    ///
+    /// The socket is read until it would block, which is what event loops with edge-triggered
+    /// notifications expect. Returns the number of packets that have been dispatched.
+    ///
+    /// Nothing is read if another thread is already reading, as it will dispatch the packets.
+    pub fn read_available(&self) -> IoResult<usize> {
+        let received = self.reader.lock().unwrap().received;
+        self.receive(received, Wait::Never)?;
+
+        let now = self.reader.lock().unwrap().received;
+        Ok(now.wrapping_sub(received))
+    }
+
+    /// Reads from the socket and dispatches what has been received, or waits for the thread
+    /// that is already doing so.
+    ///
+    /// Returns true if packets have been dispatched since the counter of received packets was
+    /// equal to `received`.
+    fn receive(&self, received: usize, wait: Wait) -> IoResult<bool> {
+        {
+            let mut reader = self.reader.lock().unwrap();
+            loop {
+                if reader.received != received {
+                    return Ok(true);
+                }
+                if !reader.reading {
+                    reader.reading = true;
+                    break;
+                }
+
+                reader = match wait {
+                    Wait::Never => return Ok(false),
+                    Wait::Until(deadline) => {
+                        let timeout = match remaining(deadline) {
+                            Ok(timeout) => timeout,
+                            Err(_) => return Ok(false),
+                        };
+                        self.reader_done.wait_timeout(reader, timeout).unwrap().0
+                    },
+                    Wait::Forever => self.reader_done.wait(reader).unwrap(),
+                };
+            }
+        }
+
+        let (count, result) = {
+            let mut buffer = self.read_buffer.lock().unwrap();
+            self.dispatch_available(&mut buffer, wait)
+        };
+
+        let disconnected = {
+            let mut reader = self.reader.lock().unwrap();
+            reader.reading = false;
+            reader.received = reader.received.wrapping_add(count);
+            reader.disconnected
+        };
+        self.reader_done.notify_all();
+
+        if count != 0 || disconnected {
+            self.wake_tasks();
+        }
+        result.map(|_| count != 0)
+    }
+
+    /// Dispatches packets until reading from the socket would block, waiting for the first one
+    /// for the time given by `wait`. Returns the number of packets that have been read, along
+    /// with the error that stopped reading.
+    fn dispatch_available(&self, buffer: &mut Vec<u8>, mut wait: Wait)
+                          -> (usize, IoResult<()>)
+    {
+        let mut count = 0;
+        loop {
+            match self.read_packet(buffer, wait) {
+                Ok(Some(packet)) => {
+                    self.dispatch(&packet);
+                    count += 1;
+                },
+                Ok(None) => return (count, Ok(())),
+                // the error is returned again by the next read, after the packets that have
+                // been received before it are handled
+                Err(_) if count != 0 => return (count, Ok(())),
+                Err(err) => return (count, Err(err)),
+            }
+
+            // the packets received along with the first one
+            wait = Wait::Never;
+        }
+    }
+
+    /// Wakes up the asynchronous tasks, after packets have been dispatched or after the
+    /// connection has been lost.
+    fn wake_tasks(&self) {
+        let wakers = self.tasks.lock().unwrap().take();
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+
+    /// Returns the next packet, reading from the socket if the ones already received are
+    /// incomplete.
+    ///
+    /// Returns `None` if no whole packet has been received in the time given by `wait`.
+    ///
+    /// `buffer` is the content of `read_buffer`, which must stay locked while reading.
+    fn read_packet(&self, buffer: &mut Vec<u8>, wait: Wait) -> IoResult<Option<Vec<u8>>> {
+        loop {
+            if let Some(length) = packet_length(buffer, self.byte_order)? {
+                if buffer.len() >= length {
+                    let rest = buffer.split_off(length);
+                    return Ok(Some(mem::replace(buffer, rest)));
+                }
+            }
+
+            let mut chunk = [0; 4096];
+            let err = match self.read_socket(&mut chunk, wait) {
+                Ok(0) => IoError::new(ErrorKind::UnexpectedEof, "The X server closed the connection"),
+                Ok(read) => {
+                    buffer.extend_from_slice(&chunk[.. read]);
+                    continue;
+                },
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock ||
+                                err.kind() == ErrorKind::TimedOut => return Ok(None),
+                Err(ref err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => err,
+            };
+
+            self.reader.lock().unwrap().disconnected = true;
+            return Err(err);
+        }
+    }
+
+    /// Reads from the socket, waiting for the server to send something for the time given by
+    /// `wait`. Returns an error of kind `WouldBlock` or `TimedOut` if nothing was received.
+    ///
+    /// The stream stays in blocking mode, and is only read once `poll` reports that something
+    /// has been received. It isn't locked while waiting, so that other threads can send requests
+    /// meanwhile.
+    ///
+    /// Streams without a file descriptor are locked while waiting instead. Nothing is read from
+    /// them with `Wait::Never`, and they block despite the timeout if they don't support
+    /// `set_read_timeout`.
+    fn read_socket(&self, chunk: &mut [u8], wait: Wait) -> IoResult<usize> {
+        #[cfg(unix)]
+        {
+            if let Some(fd) = self.raw_fd {
+                loop {
+                    // the remaining time is computed again before each attempt
+                    let timeout = match wait {
+                        Wait::Never => Some(Duration::from_secs(0)),
+                        Wait::Until(deadline) => Some(remaining(deadline)?),
+                        Wait::Forever => None,
+                    };
+
+                    if poll_fd(fd, libc::POLLIN, timeout)? {
+                        return self.socket.lock().unwrap().read(chunk);
+                    }
+
+                    if let Wait::Never = wait {
+                        return Err(IoError::from(ErrorKind::WouldBlock));
+                    }
+                }
+            }
+        }
+
+        let mut socket = self.socket.lock().unwrap();
+        match wait {
+            Wait::Never => Err(IoError::from(ErrorKind::WouldBlock)),
+            Wait::Until(deadline) => {
+                if socket.set_read_timeout(Some(remaining(deadline)?)).is_err() {
+                    return socket.read(chunk);
+                }
+
+                let result = socket.read(chunk);
+                socket.set_read_timeout(None)?;
+                result
+            },
+            Wait::Forever => socket.read(chunk),
+        }
+    }
+
+    /// Stores a packet with the reply handle that waits for it, or in the events queue.
+    ///
+    /// A reply that can't be decoded is stored as the error of its request, and the events and
+    /// errors that can't be decoded are stored as unknown ones, so that decoding errors don't
+    /// affect the other requests.
+    ///
+    /// Must be called in the order in which the packets are received, which are at least
+    /// 32 bytes long.
+    fn dispatch(&self, packet: &[u8]) {
+        let order = self.byte_order;
+
+        if packet[0] & 0x7f == KEYMAP_NOTIFY {
+            let event = Event::decode(packet, order)
+                              .unwrap_or_else(|_| unknown_event(packet, order));
+            self.pending_events.lock().unwrap().push_back(event);
+            return;
+        }
+
+        let sequence = read_u16(&packet[2 .. 4], order);
+        let sequence = self.sequence.lock().unwrap().widen(sequence);
+
+        match packet[0] {
+            // error
+            0 => {
+                // the errors and requests of extensions are identified from the extensions
+                // that have been queried
+                let (extension, number) = self.error_extension(packet[1]);
+                let request_extension = self.extension_name(packet[10]);
+                let error = XError::decode(packet, order, extension, number, request_extension)
+                                   .unwrap_or_else(|_| {
+                                       unknown_error(packet, order, request_extension)
+                                   });
+
+                let mut pending = self.pending_answers.lock().unwrap();
+                let mut waiting = self.waiting_for_answer.lock().unwrap();
+                match waiting.iter().position(|&(seq, _)| seq == sequence) {
+                    Some(index) => {
+                        waiting.remove(index);
+                        pending.push((sequence, Reply::Error(error)));
+                    },
+                    None => self.pending_events.lock().unwrap().push_back(Event::Error(error)),
+                }
+            },
+
+            // reply
+            1 => {
+                let mut pending = self.pending_answers.lock().unwrap();
+                let mut waiting = self.waiting_for_answer.lock().unwrap();
+
+                // if nobody is waiting, the handle has been dropped or this is the reply to
+                // a sync request
+                if let Some(index) = waiting.iter().position(|&(seq, _)| seq == sequence) {
+                    let reply = match waiting[index].1 {
+                        Some(ref reply_type) => {
+                            reply_type.decode(packet, order).unwrap_or_else(Reply::Invalid)
+                        },
+                        None => {
+                            Reply::Invalid(IoError::new(ErrorKind::InvalidData,
+                                                        "Reply to a request without a reply"))
+                        },
+                    };
+
+                    // some requests get several replies, the last of which is recognizable
+                    if reply.is_last() {
+                        waiting.remove(index);
+                    }
+                    pending.push((sequence, reply));
+                }
+            },
+
+            _ => {
+                let event = if packet[0] & 0x7f == GENERIC_EVENT {
+                    // generic events are identified by the major opcode of their extension
+                    let extension = self.extension_name(packet[1]);
+                    Event::decode_generic(packet, order, extension)
+                } else {
+                    Event::decode(packet, order)
+                };
+
+                let event = event.unwrap_or_else(|_| unknown_event(packet, order));
+                self.pending_events.lock().unwrap().push_back(event);
+            },
+        }
+    }
+
+    /// Returns the name of the extension whose major opcode is `major_opcode`, among the ones
+    /// that have been queried.
+    fn extension_name(&self, major_opcode: u8) -> Option<&'static str> {
+        let extensions = self.extensions.lock().unwrap();
+        extensions.iter()
+                  .find(|&(_, info)| info.as_ref().map(|i| i.major_opcode) == Some(major_opcode))
+                  .map(|(&name, _)| name)
+    }
+
+    /// Returns the name of the extension of the error whose code is `code`, among the ones that
+    /// have been queried, along with the number of the error within the extension. Returns
+    /// `None` and `code` for the errors of the core protocol.
+    fn error_extension(&self, code: u8) -> (Option<&'static str>, u8) {
+        let extensions = self.extensions.lock().unwrap();
+
+        // the extension that defines the error is the one whose errors start closest below it
+        extensions.iter()
+                  .filter_map(|(&name, info)| info.as_ref().map(|i| (name, i.first_error)))
+                  .filter(|&(_, first_error)| first_error != 0 && first_error <= code)
+                  .max_by_key(|&(_, first_error)| first_error)
+                  .map_or((None, code), |(name, first_error)| (Some(name), code - first_error))
+    }
+}
+
+/// Returns an event that couldn't be decoded as a raw one.
+fn unknown_event(packet: &[u8], order: ByteOrder) -> Event {
+    if packet[0] & 0x7f == GENERIC_EVENT {
+        let evtype = read_u16(&packet[8 .. 10], order);
+        Event::Generic { extension: packet[1], evtype, data: packet.to_vec() }
+    } else {
+        Event::Unknown { code: packet[0] & 0x7f, data: packet[.. 32].to_vec() }
+    }
+}
+
+/// Returns an error that couldn't be decoded as an unknown one.
+fn unknown_error(packet: &[u8], order: ByteOrder, request_extension: Option<&str>) -> XError {
+    let minor_opcode = read_u16(&packet[8 .. 10], order);
+    XError::Unknown(UnknownError {
+        error_code: packet[1],
+        sequence: read_u16(&packet[2 .. 4], order),
+        bad_value: read_u32(&packet[4 .. 8], order),
+        minor_opcode,
+        major_opcode: packet[10],
+        request: request_name(request_extension, packet[10], minor_opcode),
+    })
+}
+
+/// Reads a 16 bits number from the first two bytes of `bytes`.
+fn read_u16(bytes: &[u8], order: ByteOrder) -> u16 {
+    let bytes = [bytes[0], bytes[1]];
+    match order {
+        ByteOrder::BigEndian => u16::from_be_bytes(bytes),
+        ByteOrder::LittleEndian => u16::from_le_bytes(bytes),
+    }
+}
+
+/// Reads a 32 bits number from the first four bytes of `bytes`.
+fn read_u32(bytes: &[u8], order: ByteOrder) -> u32 {
+    let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+    match order {
+        ByteOrder::BigEndian => u32::from_be_bytes(bytes),
+        ByteOrder::LittleEndian => u32::from_le_bytes(bytes),
+    }
+}
+
+/// Returns the time left until `deadline`, or an error of kind `TimedOut` if it has passed.
+fn remaining(deadline: Instant) -> IoResult<Duration> {
+    let now = Instant::now();
+    if now >= deadline {
+        return Err(IoError::new(ErrorKind::TimedOut, "Timeout expired"));
+    }
+    Ok(deadline - now)
+}
+
+/// Returns the length of the packet at the start of `buffer`, or `None` if its header hasn't
+/// been entirely received yet.
+fn packet_length(buffer: &[u8], order: ByteOrder) -> IoResult<Option<usize>> {
+    if buffer.len() < 32 {
+        return Ok(None);
+    }
+
+    // replies and generic events are followed by a number of 4-byte words given in the header
+    if buffer[0] == 1 || buffer[0] & 0x7f == GENERIC_EVENT {
+        let length = u32::socket_recv(&mut IoCursor::new(&buffer[4 .. 8]), order)?;
+        Ok(Some(32 + length as usize * 4))
+    } else {
+        Ok(Some(32))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use stream::tests::MemoryStream;
+    use tests::{connect, packet};
+    use {Event, ReplyError, XError, ATOM, WINDOW};
+
+    #[test]
+    fn dispatch_to_waiters() {
+        let server = MemoryStream::default();
+        let connection = connect(&server, 0xffff);
+
+        let atom = connection.InternAtom_request(false, b"WM_NAME").unwrap();
+        let cookie = connection.MapWindow_request_checked(WINDOW(5)).unwrap();
+        connection.UnmapWindow_request(WINDOW(6)).unwrap();
+        let name = connection.GetAtomName_request(ATOM(39)).unwrap();
+
+        // an event, the reply of the first request, the errors of the two next ones, and a
+        // reply that is too short for the length of the name it contains
+        server.send(&packet(&[80, 0, 0, 0, 1, 2, 3]));
+        server.send(&packet(&[1, 0, 1, 0, 0, 0, 0, 0, 39, 0, 0, 0]));
+        server.send(&packet(&[0, 3, 2, 0, 5, 0, 0, 0, 0, 0, 8]));
+        server.send(&packet(&[0, 3, 3, 0, 6, 0, 0, 0, 0, 0, 10]));
+        server.send(&packet(&[1, 0, 4, 0, 0, 0, 0, 0, 10, 0]));
+
+        // the reply that can't be decoded only affects its own request
+        assert_eq!(atom.get().unwrap().atom, ATOM(39));
+        match name.get() {
+            Err(ReplyError::IoError(_)) => (),
+            _ => panic!("The reply of GetAtomName shouldn't be decoded"),
+        }
+
+        match cookie.check().unwrap() {
+            Err(XError::Window(ref err)) => {
+                assert_eq!(err.bad_value, 5);
+                assert_eq!(err.request, Some("MapWindow"));
+            },
+            _ => panic!("MapWindow should have failed"),
+        }
+
+        // the event and the error of the unchecked request are in the events queue
+        match connection.poll_for_queued_event() {
+            Some(Event::Unknown { code: 80, ref data }) => assert_eq!(&data[4 .. 7], &[1, 2, 3]),
+            _ => panic!("Expected the unknown event"),
+        }
+        match connection.poll_for_queued_event() {
+            Some(Event::Error(XError::Window(ref err))) => {
+                assert_eq!(err.bad_value, 6);
+                assert_eq!(err.request, Some("UnmapWindow"));
+            },
+            _ => panic!("Expected the error of UnmapWindow"),
+        }
+        assert!(connection.poll_for_queued_event().is_none());
+    }
+}
@@ -0,0 +1,119 @@
+//! Tracking of the sequence numbers of the requests.
+//!
+//! The server only sends the lowest 16 bits of the sequence numbers. The full numbers are
+//! rebuilt from the previous packet that was received, which works as long as there are less
+//! than 65536 requests between two requests that have a reply.
+
+/// Gives out the sequence numbers of the requests and rebuilds the full sequence numbers of
+/// the packets sent by the server.
+pub struct SequenceTracker {
+    /// Sequence number of the last request that was sent.
+    last_sent: u64,
+    /// Sequence number of the last request that was sent and has a reply.
+    last_with_reply: u64,
+    /// Full sequence number of the last packet that was received.
+    last_received: u64,
+}
+
+impl SequenceTracker {
+    pub fn new() -> SequenceTracker {
+        SequenceTracker {
+            last_sent: 0,
+            last_with_reply: 0,
+            last_received: 0,
+        }
+    }
+
+    /// Returns the sequence number of a new request.
+    pub fn next(&mut self, has_reply: bool) -> u64 {
+        self.last_sent += 1;
+        if has_reply {
+            self.last_with_reply = self.last_sent;
+        }
+        self.last_sent
+    }
+
+    /// Returns true if a request with a reply must be sent before the next request without
+    /// one, otherwise the sequence numbers of the packets received later would be ambiguous.
+    pub fn needs_sync(&self) -> bool {
+        self.last_sent + 1 - self.last_with_reply >= 0xffff
+    }
+
+    /// Returns the full sequence number of a packet sent by the server.
+    ///
+    /// Packets arrive in the order of the requests, so this is the first number since the
+    /// previous packet whose lowest 16 bits are `sequence`.
+    pub fn widen(&mut self, sequence: u16) -> u64 {
+        let offset = sequence.wrapping_sub(self.last_received as u16);
+        self.last_received += offset as u64;
+        self.last_received
+    }
+
+    /// Returns the full sequence number of the last packet that was received.
+    pub fn last_received(&self) -> u64 {
+        self.last_received
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SequenceTracker;
+
+    #[test]
+    fn widen_across_wrap() {
+        let mut sequence = SequenceTracker::new();
+        for expected in 1 .. 200_000u64 {
+            assert_eq!(sequence.next(true), expected);
+            assert_eq!(sequence.widen(expected as u16), expected);
+        }
+    }
+
+    #[test]
+    fn widen_after_gaps() {
+        let mut sequence = SequenceTracker::new();
+        assert_eq!(sequence.widen(0xfffe), 0xfffe);
+        assert_eq!(sequence.widen(0xffff), 0xffff);
+        assert_eq!(sequence.widen(0), 0x1_0000);
+        assert_eq!(sequence.widen(5), 0x1_0005);
+
+        // several replies to the same request
+        assert_eq!(sequence.widen(5), 0x1_0005);
+        assert_eq!(sequence.last_received(), 0x1_0005);
+
+        // the next packet is for a request just before the sequence number wraps again
+        assert_eq!(sequence.widen(4), 0x2_0004);
+    }
+
+    #[test]
+    fn sync_after_void_requests() {
+        let mut sequence = SequenceTracker::new();
+        assert_eq!(sequence.next(true), 1);
+
+        // the packets of the requests without a reply can only be told apart from the ones of
+        // the request with a reply for less than 65536 requests
+        let mut count = 0;
+        while !sequence.needs_sync() {
+            sequence.next(false);
+            count += 1;
+        }
+        assert_eq!(count, 0xfffe);
+
+        let sync = sequence.next(true);
+        assert_eq!(sync, 0x1_0000);
+        assert!(!sequence.needs_sync());
+
+        // the reply of the first request, the error of the last one without a reply and the
+        // reply of the sync
+        assert_eq!(sequence.widen(1), 1);
+        assert_eq!(sequence.widen(0xffff), 0xffff);
+        assert_eq!(sequence.widen(0), 0x1_0000);
+
+        for _ in 0 .. 0xfffe {
+            assert!(!sequence.needs_sync());
+            sequence.next(false);
+        }
+        assert!(sequence.needs_sync());
+        assert_eq!(sequence.next(true), 0x1_ffff);
+        assert_eq!(sequence.widen(0xffff), 0x1_ffff);
+    }
+}
@@ -0,0 +1,567 @@
+//! The streams that can be used to communicate with the X server.
+
+use std::io::{Error as IoError, ErrorKind, Read, Write};
+use std::io::Result as IoResult;
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+
+#[cfg(unix)]
+use libc;
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, RawFd};
+
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(unix)]
+use std::path::Path;
+
+/// A bidirectional channel to the X server.
+///
+/// This is implemented for `TcpStream` and `UnixStream`, and can be implemented for any other
+/// transport, for example an in-memory pipe or an SSH channel.
+pub trait Stream: Read + Write + Send {
+    /// Returns the address of the server, if the stream is a TCP connection.
+    ///
+    /// Used to find the credentials of the server in the Xauthority file.
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    /// Returns our own address, if the stream is a TCP connection.
+    ///
+    /// Used by the XDM-AUTHORIZATION-1 authorization protocol.
+    fn local_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    /// Switches the stream between blocking and non-blocking mode.
+    ///
+    /// In non-blocking mode, reading and writing return an error of kind `WouldBlock` instead
+    /// of blocking. Streams that can't be switched return an error, and are then used in
+    /// blocking mode.
+    ///
+    /// Used by the asynchronous drivers, so that the futures send their requests without
+    /// blocking.
+    fn set_nonblocking(&self, _: bool) -> IoResult<()> {
+        Err(IoError::other("This stream doesn't support non-blocking mode"))
+    }
+
+    /// Sets the time after which reading gives up with an error of kind `WouldBlock` or
+    /// `TimedOut`. `None` means that reading blocks indefinitely.
+    ///
+    /// Only used for streams without a file descriptor, which can't be waited on with `poll`.
+    fn set_read_timeout(&self, _: Option<Duration>) -> IoResult<()> {
+        Err(IoError::other("This stream doesn't support timeouts"))
+    }
+
+    /// Returns the file descriptor of the socket, if there is one.
+    ///
+    /// The descriptor is used to wait for the server with `poll`, which lets other threads send
+    /// requests meanwhile and doesn't require switching the stream to non-blocking mode.
+    #[cfg(unix)]
+    fn raw_fd(&self) -> Option<RawFd> {
+        None
+    }
+
+    /// Writes all the buffers one after the other, if possible with a single system call.
+    ///
+    /// Must wait for the stream to be writable if it is in non-blocking mode.
+    fn write_all_slices(&mut self, bufs: &[&[u8]]) -> IoResult<()> {
+        for buf in bufs.iter() {
+            let mut buf = *buf;
+            while !buf.is_empty() {
+                match self.write(buf) {
+                    Ok(0) => return Err(IoError::from(ErrorKind::WriteZero)),
+                    Ok(written) => buf = &buf[written ..],
+                    Err(ref err) if err.kind() == ErrorKind::Interrupted => (),
+                    Err(ref err) if err.kind() == ErrorKind::WouldBlock => wait_writable(self)?,
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Stream for TcpStream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        TcpStream::peer_addr(self).ok()
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        TcpStream::local_addr(self).ok()
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> IoResult<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> IoResult<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    #[cfg(unix)]
+    fn raw_fd(&self) -> Option<RawFd> {
+        Some(self.as_raw_fd())
+    }
+
+    #[cfg(unix)]
+    fn write_all_slices(&mut self, bufs: &[&[u8]]) -> IoResult<()> {
+        writev_all(self.as_raw_fd(), bufs)
+    }
+}
+
+#[cfg(unix)]
+impl Stream for UnixStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> IoResult<()> {
+        UnixStream::set_nonblocking(self, nonblocking)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> IoResult<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+
+    fn raw_fd(&self) -> Option<RawFd> {
+        Some(self.as_raw_fd())
+    }
+
+    fn write_all_slices(&mut self, bufs: &[&[u8]]) -> IoResult<()> {
+        writev_all(self.as_raw_fd(), bufs)
+    }
+}
+
+/// Waits until one of `events` happens on a file descriptor, for at most `timeout` if it isn't
+/// `None`.
+///
+/// Returns false if the timeout expired or if waiting was interrupted by a signal.
+#[cfg(unix)]
+pub fn poll_fd(fd: RawFd, events: libc::c_short, timeout: Option<Duration>) -> IoResult<bool> {
+    // the timeout is rounded up to the next millisecond, so that waiting doesn't end early
+    let timeout = match timeout {
+        Some(timeout) => {
+            let millis = timeout.as_nanos().div_ceil(1_000_000);
+            if millis > libc::c_int::MAX as u128 { libc::c_int::MAX } else { millis as libc::c_int }
+        },
+        None => -1,
+    };
+
+    let mut pollfd = libc::pollfd { fd, events, revents: 0 };
+    match unsafe { libc::poll(&mut pollfd, 1, timeout) } {
+        0 => Ok(false),
+        result if result > 0 => Ok(true),
+        _ => {
+            let err = IoError::last_os_error();
+            if err.kind() == ErrorKind::Interrupted {
+                Ok(false)
+            } else {
+                Err(err)
+            }
+        },
+    }
+}
+
+/// Waits until a stream in non-blocking mode can be written to.
+#[cfg(unix)]
+fn wait_writable<S>(stream: &S) -> IoResult<()> where S: Stream + ?Sized {
+    match stream.raw_fd() {
+        Some(fd) => poll_fd(fd, libc::POLLOUT, None).map(|_| ()),
+        None => Err(IoError::from(ErrorKind::WouldBlock)),
+    }
+}
+
+#[cfg(not(unix))]
+fn wait_writable<S>(_: &S) -> IoResult<()> where S: Stream + ?Sized {
+    Err(IoError::from(ErrorKind::WouldBlock))
+}
+
+/// Writes all the buffers to a file descriptor with `writev`, waiting for it to be writable
+/// if it is in non-blocking mode.
+#[cfg(unix)]
+fn writev_all(fd: RawFd, bufs: &[&[u8]]) -> IoResult<()> {
+    let mut bufs = bufs.iter().cloned().filter(|buf| !buf.is_empty()).collect::<Vec<_>>();
+    let mut first = 0;
+
+    while first < bufs.len() {
+        let iovecs = bufs[first ..].iter().map(|buf| {
+            libc::iovec { iov_base: buf.as_ptr() as *mut libc::c_void, iov_len: buf.len() }
+        }).collect::<Vec<_>>();
+
+        let written = unsafe { libc::writev(fd, iovecs.as_ptr(), iovecs.len() as libc::c_int) };
+        if written < 0 {
+            let err = IoError::last_os_error();
+            match err.kind() {
+                ErrorKind::Interrupted => continue,
+                ErrorKind::WouldBlock => {
+                    poll_fd(fd, libc::POLLOUT, None)?;
+                    continue;
+                },
+                _ => return Err(err),
+            }
+        }
+
+        // skipping what has been written, which can end in the middle of a buffer
+        let mut written = written as usize;
+        while first < bufs.len() && written >= bufs[first].len() {
+            written -= bufs[first].len();
+            first += 1;
+        }
+        if first < bufs.len() {
+            bufs[first] = &bufs[first][written ..];
+        }
+    }
+
+    Ok(())
+}
+
+/// Size of the buffer in which the requests are gathered before being sent.
+const BUFFER_SIZE: usize = 16384;
+
+/// Wraps a stream to gather what is written in a buffer, which is sent when it is full or
+/// when the stream is flushed. Reading isn't buffered.
+pub struct BufferedStream<S: ?Sized = dyn Stream> {
+    buffer: Vec<u8>,
+    // comes last, so that the stream can be unsized
+    pub stream: S,
+}
+
+impl<S> BufferedStream<S> {
+    pub fn new(stream: S) -> BufferedStream<S> {
+        BufferedStream {
+            buffer: Vec::with_capacity(BUFFER_SIZE),
+            stream,
+        }
+    }
+}
+
+impl<S> BufferedStream<S> where S: ?Sized + Stream {
+
+    /// Sends the content of the buffer, without flushing the stream.
+    fn send_buffer(&mut self) -> IoResult<()> {
+        if !self.buffer.is_empty() {
+            self.stream.write_all_slices(&[&self.buffer[..]])?;
+            self.buffer.clear();
+        }
+        Ok(())
+    }
+
+    /// Sends as much of the buffer as possible without blocking, if the stream is in
+    /// non-blocking mode. Returns true if everything has been sent and the stream flushed.
+    ///
+    /// In blocking mode, this is the same as `flush`.
+    pub fn try_flush(&mut self) -> IoResult<bool> {
+        while !self.buffer.is_empty() {
+            match self.stream.write(&self.buffer) {
+                Ok(0) => return Err(IoError::from(ErrorKind::WriteZero)),
+                Ok(written) => {
+                    self.buffer.drain(.. written);
+                },
+                Err(ref err) if err.kind() == ErrorKind::Interrupted => (),
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock => return Ok(false),
+                Err(err) => return Err(err),
+            }
+        }
+
+        self.stream.flush()?;
+        Ok(true)
+    }
+}
+
+impl<S> Read for BufferedStream<S> where S: ?Sized + Stream {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        self.stream.read(buf)
+    }
+}
+
+impl<S> Write for BufferedStream<S> where S: ?Sized + Stream {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        // large data, like images, is sent right away instead of being copied
+        if buf.len() >= BUFFER_SIZE {
+            self.stream.write_all_slices(&[&self.buffer[..], buf])?;
+            self.buffer.clear();
+            return Ok(buf.len());
+        }
+
+        if self.buffer.len() + buf.len() > BUFFER_SIZE {
+            self.send_buffer()?;
+        }
+
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        self.send_buffer()?;
+        self.stream.flush()
+    }
+}
+
+impl<S> Stream for BufferedStream<S> where S: ?Sized + Stream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.stream.peer_addr()
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        self.stream.local_addr()
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> IoResult<()> {
+        self.stream.set_nonblocking(nonblocking)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> IoResult<()> {
+        self.stream.set_read_timeout(timeout)
+    }
+
+    #[cfg(unix)]
+    fn raw_fd(&self) -> Option<RawFd> {
+        self.stream.raw_fd()
+    }
+}
+
+/// The stream of the connections opened from the name of a display, which is either a TCP or a
+/// local socket.
+pub enum DisplayStream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Read for DisplayStream {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        match *self {
+            DisplayStream::Tcp(ref mut stream) => stream.read(buf),
+            #[cfg(unix)]
+            DisplayStream::Unix(ref mut stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for DisplayStream {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        match *self {
+            DisplayStream::Tcp(ref mut stream) => stream.write(buf),
+            #[cfg(unix)]
+            DisplayStream::Unix(ref mut stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        match *self {
+            DisplayStream::Tcp(ref mut stream) => stream.flush(),
+            #[cfg(unix)]
+            DisplayStream::Unix(ref mut stream) => stream.flush(),
+        }
+    }
+}
+
+impl Stream for DisplayStream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        match *self {
+            DisplayStream::Tcp(ref stream) => Stream::peer_addr(stream),
+            #[cfg(unix)]
+            DisplayStream::Unix(ref stream) => Stream::peer_addr(stream),
+        }
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        match *self {
+            DisplayStream::Tcp(ref stream) => Stream::local_addr(stream),
+            #[cfg(unix)]
+            DisplayStream::Unix(ref stream) => Stream::local_addr(stream),
+        }
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> IoResult<()> {
+        match *self {
+            DisplayStream::Tcp(ref stream) => Stream::set_nonblocking(stream, nonblocking),
+            #[cfg(unix)]
+            DisplayStream::Unix(ref stream) => Stream::set_nonblocking(stream, nonblocking),
+        }
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> IoResult<()> {
+        match *self {
+            DisplayStream::Tcp(ref stream) => Stream::set_read_timeout(stream, timeout),
+            #[cfg(unix)]
+            DisplayStream::Unix(ref stream) => Stream::set_read_timeout(stream, timeout),
+        }
+    }
+
+    #[cfg(unix)]
+    fn raw_fd(&self) -> Option<RawFd> {
+        Some(self.as_raw_fd())
+    }
+
+    fn write_all_slices(&mut self, bufs: &[&[u8]]) -> IoResult<()> {
+        match *self {
+            DisplayStream::Tcp(ref mut stream) => stream.write_all_slices(bufs),
+            #[cfg(unix)]
+            DisplayStream::Unix(ref mut stream) => stream.write_all_slices(bufs),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl AsRawFd for DisplayStream {
+    fn as_raw_fd(&self) -> RawFd {
+        match *self {
+            DisplayStream::Tcp(ref stream) => stream.as_raw_fd(),
+            DisplayStream::Unix(ref stream) => stream.as_raw_fd(),
+        }
+    }
+}
+
+/// Connects to the local socket at `path`.
+///
+/// On Linux, the abstract socket whose name is `path` is tried first, as this is where
+/// most X servers listen. The socket in the filesystem is tried next.
+#[cfg(unix)]
+pub fn connect_unix(path: &Path) -> IoResult<UnixStream> {
+    if let Ok(stream) = connect_abstract(path) {
+        return Ok(stream);
+    }
+
+    UnixStream::connect(path)
+}
+
+/// Connects to a socket in the abstract namespace of Linux.
+#[cfg(target_os = "linux")]
+fn connect_abstract(path: &Path) -> IoResult<UnixStream> {
+    use std::mem;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::io::FromRawFd;
+
+    let name = path.as_os_str().as_bytes();
+
+    unsafe {
+        let mut addr: libc::sockaddr_un = mem::zeroed();
+        addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+
+        // the name of an abstract socket starts with a nul byte
+        if name.len() + 1 > addr.sun_path.len() {
+            return Err(IoError::new(ErrorKind::InvalidInput, "Socket path is too long"));
+        }
+
+        for (dest, &byte) in addr.sun_path[1 ..].iter_mut().zip(name.iter()) {
+            *dest = byte as libc::c_char;
+        }
+
+        let fd = libc::socket(libc::AF_UNIX, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0);
+        if fd < 0 {
+            return Err(IoError::last_os_error());
+        }
+
+        let len = mem::size_of::<libc::sa_family_t>() + 1 + name.len();
+        if libc::connect(fd, &addr as *const _ as *const libc::sockaddr,
+                         len as libc::socklen_t) < 0
+        {
+            let err = IoError::last_os_error();
+            libc::close(fd);
+            return Err(err);
+        }
+
+        Ok(UnixStream::from_raw_fd(fd))
+    }
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn connect_abstract(_: &Path) -> IoResult<UnixStream> {
+    Err(IoError::new(ErrorKind::Other, "Abstract sockets are only supported on Linux"))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::collections::VecDeque;
+    use std::io::{Read, Result as IoResult, Write};
+    use std::sync::{Arc, Mutex};
+
+    use super::{BufferedStream, Stream, BUFFER_SIZE};
+
+    /// A stream in memory, whose clones share the same buffers. The connection reads what the
+    /// test sends as the server, and the test checks what the connection writes.
+    ///
+    /// Reading returns at most the bytes of one call to `send`, so that the connection only
+    /// reads the packets that follow once it needs them, like from a server that answers the
+    /// requests one by one. It returns the end of the stream once everything has been read.
+    #[derive(Clone, Default)]
+    pub struct MemoryStream {
+        buffers: Arc<Mutex<Buffers>>,
+    }
+
+    #[derive(Default)]
+    struct Buffers {
+        // what the server sends, one entry per call to `send`
+        sent: VecDeque<Vec<u8>>,
+        // what the connection writes
+        written: Vec<u8>,
+    }
+
+    impl MemoryStream {
+        /// Queues bytes to be read by the connection.
+        pub fn send(&self, bytes: &[u8]) {
+            self.buffers.lock().unwrap().sent.push_back(bytes.to_vec());
+        }
+
+        /// Returns what has been written since the last call.
+        pub fn take_written(&self) -> Vec<u8> {
+            let mut buffers = self.buffers.lock().unwrap();
+            buffers.written.drain(..).collect()
+        }
+    }
+
+    impl Read for MemoryStream {
+        fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+            let mut buffers = self.buffers.lock().unwrap();
+            let sent = match buffers.sent.front_mut() {
+                Some(sent) => sent,
+                None => return Ok(0),
+            };
+
+            let read = buf.len().min(sent.len());
+            buf[.. read].copy_from_slice(&sent[.. read]);
+            sent.drain(.. read);
+            if sent.is_empty() {
+                buffers.sent.pop_front();
+            }
+            Ok(read)
+        }
+    }
+
+    impl Write for MemoryStream {
+        fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+            self.buffers.lock().unwrap().written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> IoResult<()> {
+            Ok(())
+        }
+    }
+
+    impl Stream for MemoryStream {}
+
+    #[test]
+    fn buffering() {
+        let stream = MemoryStream::default();
+        let mut buffered = BufferedStream::new(stream.clone());
+
+        buffered.write_all(&[1, 2, 3]).unwrap();
+        assert_eq!(stream.take_written(), vec![]);
+        buffered.flush().unwrap();
+        assert_eq!(stream.take_written(), vec![1, 2, 3]);
+
+        // large data is sent right away, after what is already in the buffer
+        buffered.write_all(&[4]).unwrap();
+        buffered.write_all(&vec![5; BUFFER_SIZE]).unwrap();
+        let written = stream.take_written();
+        assert_eq!(written.len(), BUFFER_SIZE + 1);
+        assert_eq!(&written[.. 2], &[4, 5]);
+
+        // the buffer is sent when it is full
+        buffered.write_all(&vec![6; BUFFER_SIZE - 1]).unwrap();
+        buffered.write_all(&[7, 8]).unwrap();
+        assert_eq!(stream.take_written(), vec![6; BUFFER_SIZE - 1]);
+        buffered.flush().unwrap();
+        assert_eq!(stream.take_written(), vec![7, 8]);
+    }
+}
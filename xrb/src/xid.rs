@@ -0,0 +1,171 @@
+//! Allocation of the IDs of the resources created by the client.
+
+use std::io::Result as IoResult;
+
+use {ReplyError, XConnection};
+
+/// Gives out the IDs of a range of resource IDs.
+pub struct XidAllocator {
+    base: u32,
+    /// Difference between two consecutive IDs, which is the lowest bit of the mask.
+    increment: u32,
+    /// The next ID to give out, without the base, or `None` if it would overflow.
+    next: Option<u32>,
+    /// The last ID of the range, without the base.
+    max: u32,
+    /// Number of times the range has been replaced.
+    ranges: usize,
+}
+
+impl XidAllocator {
+    /// Builds an allocator for the range given by the server in the setup.
+    pub fn new(base: u32, mask: u32) -> XidAllocator {
+        XidAllocator {
+            base,
+            increment: mask & (!mask).wrapping_add(1),
+            next: Some(0),
+            max: mask,
+            ranges: 0,
+        }
+    }
+
+    /// Returns a new ID, or `None` if the range is exhausted.
+    pub fn next(&mut self) -> Option<u32> {
+        let next = match self.next {
+            Some(next) if self.increment != 0 && next <= self.max => next,
+            _ => return None,
+        };
+
+        self.next = next.checked_add(self.increment);
+        Some(next | self.base)
+    }
+
+    /// Replaces the exhausted range with one returned by the XC-MISC extension.
+    ///
+    /// Returns false if the range is empty or doesn't fit in 32 bits, in which case it is
+    /// ignored.
+    pub fn set_range(&mut self, start: u32, count: u32) -> bool {
+        let max = count.checked_sub(1)
+                       .and_then(|count| count.checked_mul(self.increment))
+                       .and_then(|offset| start.checked_add(offset));
+
+        match max {
+            Some(max) if self.increment != 0 => {
+                self.next = Some(start);
+                self.max = max;
+                self.ranges += 1;
+                true
+            },
+            _ => false,
+        }
+    }
+}
+
+impl XConnection {
+    /// Returns a new resource ID, for example to create a window, a pixmap or a graphics
+    /// context.
+    ///
+    /// When all the IDs given by the server during the handshake have been used, the IDs of
+    /// resources that have been destroyed since are requested with the XC-MISC extension.
+    /// Returns `Ok(None)` if there is none left.
+    pub fn generate_id(&self) -> IoResult<Option<u32>> {
+        loop {
+            let ranges = {
+                let mut xid = self.xid.lock().unwrap();
+                if let Some(id) = xid.next() {
+                    return Ok(Some(id));
+                }
+                xid.ranges
+            };
+
+            // the other threads can use the IDs of the current range while waiting for the
+            // server
+            let handle = match self.XCMiscGetXIDRange_request()? {
+                Some(handle) => handle,
+                None => return Ok(None)
+            };
+
+            let range = match handle.get() {
+                Ok(range) => range,
+                Err(ReplyError::IoError(err)) => return Err(err),
+                Err(ReplyError::XError(_)) => return Ok(None)
+            };
+
+            // this is what the server returns when there are no more IDs
+            if range.count == 0 || (range.start_id == 0 && range.count == 1) {
+                return Ok(None);
+            }
+
+            let mut xid = self.xid.lock().unwrap();
+            // if another thread has obtained a range meanwhile, it may contain the same IDs
+            // as ours, so it must be used first
+            if xid.ranges != ranges {
+                continue;
+            }
+
+            if !xid.set_range(range.start_id, range.count) {
+                return Ok(None);
+            }
+            return Ok(xid.next());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::XidAllocator;
+
+    #[test]
+    fn setup_range() {
+        let mut xid = XidAllocator::new(0x0400_0000, 0x3);
+        assert_eq!(xid.next(), Some(0x0400_0000));
+        assert_eq!(xid.next(), Some(0x0400_0001));
+        assert_eq!(xid.next(), Some(0x0400_0002));
+        assert_eq!(xid.next(), Some(0x0400_0003));
+        assert_eq!(xid.next(), None);
+        assert_eq!(xid.next(), None);
+    }
+
+    #[test]
+    fn increment() {
+        // the IDs follow the lowest bit of the mask
+        let mut xid = XidAllocator::new(0x0020_0000, 0x1c);
+        let ids = (0 .. 9).map(|_| xid.next()).collect::<Vec<_>>();
+        assert_eq!(ids, vec![Some(0x0020_0000), Some(0x0020_0004), Some(0x0020_0008),
+                             Some(0x0020_000c), Some(0x0020_0010), Some(0x0020_0014),
+                             Some(0x0020_0018), Some(0x0020_001c), None]);
+
+        let mut xid = XidAllocator::new(0x0020_0000, 0);
+        assert_eq!(xid.next(), None);
+    }
+
+    #[test]
+    fn refill() {
+        let mut xid = XidAllocator::new(0x0400_0000, 0x6);
+        while xid.next().is_some() {}
+
+        assert!(xid.set_range(0x10, 3));
+        assert_eq!(xid.next(), Some(0x0400_0010));
+        assert_eq!(xid.next(), Some(0x0400_0012));
+        assert_eq!(xid.next(), Some(0x0400_0014));
+        assert_eq!(xid.next(), None);
+
+        assert!(xid.set_range(0x8, 1));
+        assert_eq!(xid.next(), Some(0x0400_0008));
+        assert_eq!(xid.next(), None);
+    }
+
+    #[test]
+    fn invalid_ranges() {
+        let mut xid = XidAllocator::new(0, 0xffff_ffff);
+        assert!(!xid.set_range(5, 0));
+        assert!(!xid.set_range(0xffff_fff0, 0x20));
+        assert!(!xid.set_range(2, 0xffff_ffff));
+
+        // the last IDs before the overflow
+        assert!(xid.set_range(0xffff_fffe, 2));
+        assert_eq!(xid.next(), Some(0xffff_fffe));
+        assert_eq!(xid.next(), Some(0xffff_ffff));
+        assert_eq!(xid.next(), None);
+    }
+}